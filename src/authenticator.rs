@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use blake2::Blake2b;
+use blake2::crypto_mac::Mac;
+use libp2p::PeerId;
+
+// How commit messages are authenticated. View-change, new-view, checkpoint, pre-prepare and
+// prepare messages are always signed as other replicas must be able to verify them when they are
+// forwarded as proofs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthenticationMode {
+    Signatures,
+    // Each message carries a vector of MACs, one per replica, computed with the session keys
+    Authenticators,
+}
+
+impl std::str::FromStr for AuthenticationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "signatures" => Ok(AuthenticationMode::Signatures),
+            "authenticators" => Ok(AuthenticationMode::Authenticators),
+            _ => Err(format!("Invalid authentication mode: {}", s)),
+        }
+    }
+}
+
+// Each replica _i_ chooses the key that replica _j_ uses to authenticate the messages it sends to
+// _i_, and sends it to _j_ in a NEW-KEY message over the (encrypted) libp2p connection
+pub struct SessionKeys {
+    // keys chosen by this replica, used to verify the messages received from the peers
+    inbound: HashMap<PeerId, Vec<u8>>,
+    // keys chosen by the peers, used to authenticate the messages sent to them
+    outbound: HashMap<PeerId, Vec<u8>>,
+}
+
+impl SessionKeys {
+    pub fn new() -> Self {
+        Self {
+            inbound: HashMap::new(),
+            outbound: HashMap::new(),
+        }
+    }
+
+    pub fn generate(&mut self, peer_id: PeerId) -> Vec<u8> {
+        let key = generate_key();
+        println!("[SessionKeys::generate] a new session key has been generated for {:?}", peer_id);
+        self.inbound.insert(peer_id, key.clone());
+        key
+    }
+
+    pub fn insert_outbound(&mut self, peer_id: PeerId, key: Vec<u8>) {
+        println!("[SessionKeys::insert_outbound] the session key chosen by {:?} has been stored", peer_id);
+        self.outbound.insert(peer_id, key);
+    }
+
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.inbound.remove(peer_id);
+        self.outbound.remove(peer_id);
+    }
+
+    pub fn inbound(&self, peer_id: &PeerId) -> Option<&Vec<u8>> {
+        self.inbound.get(peer_id)
+    }
+
+    pub fn outbound(&self) -> &HashMap<PeerId, Vec<u8>> {
+        &self.outbound
+    }
+}
+
+fn generate_key() -> Vec<u8> {
+    rand::random::<[u8; 32]>().to_vec()
+}
+
+pub fn mac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Blake2b::new_varkey(key).expect("The session key has a valid length");
+    mac.input(message);
+    mac.result().code().to_vec()
+}
+
+pub fn verify_mac(key: &[u8], message: &[u8], code: &[u8]) -> bool {
+    let mut mac = Blake2b::new_varkey(key).expect("The session key has a valid length");
+    mac.input(message);
+    mac.verify(code).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Authenticated, Commit, PrePrepare, Prepare};
+    use libp2p::identity::Keypair;
+
+    fn peer_id() -> PeerId {
+        PeerId::from_public_key(Keypair::generate_ed25519().public())
+    }
+
+    fn commit() -> Commit {
+        Commit::from(Prepare::from(&PrePrepare::null(1, 1)))
+    }
+
+    #[test]
+    fn mac_round_trip() {
+        let key = generate_key();
+        let code = mac(&key, b"message");
+        assert!(verify_mac(&key, b"message", &code));
+        assert!(!verify_mac(&key, b"other message", &code));
+        assert!(!verify_mac(&generate_key(), b"message", &code));
+    }
+
+    #[test]
+    fn authenticator_round_trip() {
+        let (sender, receiver) = (peer_id(), peer_id());
+        // The receiver chooses the key and sends it to the sender in a NEW-KEY message
+        let mut receiver_keys = SessionKeys::new();
+        let key = receiver_keys.generate(sender.clone());
+        let mut sender_keys = SessionKeys::new();
+        sender_keys.insert_outbound(receiver.clone(), key);
+
+        let authenticated = Authenticated::new(commit(), &sender, sender_keys.outbound());
+        assert!(authenticated.verify(&receiver, receiver_keys.inbound(&sender).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn authenticator_for_another_receiver_is_rejected() {
+        let (sender, receiver, other) = (peer_id(), peer_id(), peer_id());
+        let mut sender_keys = SessionKeys::new();
+        let key = generate_key();
+        sender_keys.insert_outbound(receiver.clone(), key.clone());
+
+        let authenticated = Authenticated::new(commit(), &sender, sender_keys.outbound());
+        // There is no MAC for the other replica, even if it held the same key
+        assert!(authenticated.verify(&other, &key).is_err());
+        // The MAC for the receiver doesn't verify with another key
+        assert!(authenticated.verify(&receiver, &generate_key()).is_err());
+    }
+
+    #[test]
+    fn session_keys_are_removed_on_disconnection() {
+        let peer = peer_id();
+        let mut keys = SessionKeys::new();
+        keys.generate(peer.clone());
+        keys.insert_outbound(peer.clone(), generate_key());
+
+        keys.remove(&peer);
+        assert!(keys.inbound(&peer).is_none());
+        assert!(keys.outbound().is_empty());
+    }
+}
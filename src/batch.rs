@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+use tokio::prelude::{Async, Future};
+use crate::message::ClientRequest;
+
+// The primary batches client requests so that the cost of the protocol is amortized over several
+// requests under load. A batch is sent when it is full or when the oldest request in it has waited
+// for `max_delay`.
+pub struct Batcher {
+    max_size: usize,
+    max_delay: Duration,
+    pending: Vec<ClientRequest>,
+    delay: Option<Delay>,
+}
+
+impl Batcher {
+    pub fn new(max_size: usize, max_delay: Duration) -> Self {
+        assert!(max_size > 0, "The max batch size must be greater than 0");
+        println!("[Batcher::new] max_size: {}, max_delay: {:?}", max_size, max_delay);
+        Self {
+            max_size,
+            max_delay,
+            pending: Vec::new(),
+            delay: None,
+        }
+    }
+
+    pub fn push(&mut self, client_request: ClientRequest) {
+        let digest = client_request.digest();
+        if self.pending.iter().any(|r| r.digest() == digest) {
+            println!("[Batcher::push] the request is already pending. digest: {}", digest);
+            return;
+        }
+
+        if self.pending.is_empty() {
+            self.delay = Some(Delay::new(Instant::now() + self.max_delay));
+        }
+        self.pending.push(client_request);
+        println!("[Batcher::push] pending: {}", self.pending.len());
+    }
+
+    // Takes all the pending requests, e.g. as the replica is no longer the primary
+    pub fn drain(&mut self) -> Vec<ClientRequest> {
+        self.delay = None;
+        self.pending.drain(..).collect()
+    }
+
+    // Returns `Ready` with the requests to be ordered if the batch is full or the delay has expired
+    pub fn poll(&mut self) -> Async<Vec<ClientRequest>> {
+        if self.pending.is_empty() {
+            return Async::NotReady;
+        }
+
+        if self.pending.len() < self.max_size {
+            match self.delay.as_mut().map(|d| d.poll()) {
+                Some(Ok(Async::NotReady)) => return Async::NotReady,
+                Some(Ok(Async::Ready(()))) | None => {}
+                Some(Err(e)) => eprintln!("[Batcher::poll] timer error: {:?}", e),
+            }
+        }
+
+        let size = std::cmp::min(self.max_size, self.pending.len());
+        let batch: Vec<ClientRequest> = self.pending.drain(..size).collect();
+        self.delay = if self.pending.is_empty() {
+            None
+        } else {
+            Some(Delay::new(Instant::now() + self.max_delay))
+        };
+        println!("[Batcher::poll] the batch is ready. size: {}, pending: {}", batch.len(), self.pending.len());
+        Async::Ready(batch)
+    }
+}
@@ -4,12 +4,41 @@ use libp2p::multiaddr::Multiaddr;
 use std::error::Error;
 use tokio::prelude::{AsyncRead, AsyncWrite, Async};
 use libp2p::PeerId;
-use std::collections::{VecDeque, HashSet, HashMap};
-use crate::message::{ClientRequest, PrePrepareSequence, PrePrepare, Prepare, Commit, ClientReply};
-use crate::handler::{PbftHandlerIn, PbftHandler, PbftHandlerEvent};
+use std::collections::{VecDeque, HashSet, HashMap, BTreeMap};
+use crate::message::{ClientRequest, PrePrepareSequence, PrePrepare, Prepare, Commit, ClientReply, PreparedCertificate, ViewChange, NewView, Checkpoint, NewKey, FetchState, StateSnapshot, CheckpointState, CheckpointSummary, FetchPartitions, Partitions, Signed, Envelope, Authenticated};
+use serde::Serialize;
+use std::fmt::Display;
+use crate::handler::{PbftHandlerIn, PbftHandler, PbftHandlerEvent, ConnectionId};
 use crate::state::State;
 use libp2p::identity::Keypair;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::timer::RequestTimers;
+use crate::quorum::Quorum;
+use crate::water_mark::WaterMarks;
+use crate::state_machine::StateMachine;
+use crate::authenticator::{AuthenticationMode, SessionKeys};
+use crate::membership::Membership;
+use crate::node_type::NodeType;
+use crate::batch::Batcher;
+use crate::pipeline::Pipeline;
+use crate::state_transfer::{StateTransfer, checkpoint_digest};
+use crate::partition_tree::PartitionTree;
+use crate::retransmission::{Retransmissions, Tracked};
+use crate::error::{PbftError, ErrorCounts};
+
+// The timeout `T` after which a backup suspects the primary and starts a view change
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Checkpoints are generated when a request with a sequence number divisible by this constant is executed
+pub const CHECKPOINT_PERIOD: u64 = 100;
+// A pre-prepare, prepare or commit message is retransmitted to a peer that has not acknowledged it within this timeout
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(2);
+// The number of times a message is sent to a peer before giving up. A peer that has missed the message
+// catches up with state transfer.
+const MAX_RETRANSMISSIONS: u32 = 5;
+// A view-change message is accepted for at most this many views after the current view, so that a
+// faulty replica can't fill the log with view changes for arbitrary views
+const MAX_VIEW_CHANGE_DISTANCE: u64 = 10;
 
 pub struct Pbft<TSubstream> {
     keypair: Keypair,
@@ -19,23 +48,105 @@ pub struct Pbft<TSubstream> {
     state: State,
     pre_prepare_sequence: PrePrepareSequence,
     client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
+    request_timers: RequestTimers,
+    quorum: Quorum,
+    water_marks: WaterMarks,
+    // Requests that have been committed but cannot be executed yet as lower sequence numbers are still pending
+    execution_queue: BTreeMap<u64, Commit>,
+    service: Box<dyn StateMachine + Send>,
+    authentication: AuthenticationMode,
+    session_keys: SessionKeys,
+    membership: Membership,
+    // Client requests waiting to be ordered by the primary
+    batcher: Batcher,
+    pipeline: Pipeline,
+    state_transfer: StateTransfer,
+    retransmissions: Retransmissions,
+    // the messages from peers that have been rejected
+    errors: ErrorCounts,
     _marker: std::marker::PhantomData<TSubstream>,
 }
 
 impl<TSubstream> Pbft<TSubstream> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         keypair: Keypair,
         client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
+        quorum: Quorum,
+        water_marks: WaterMarks,
+        service: Box<dyn StateMachine + Send>,
+        authentication: AuthenticationMode,
+        membership: Membership,
+        batcher: Batcher,
+        pipeline: Pipeline,
+        state: State,
     ) -> Self {
-        Self {
+        println!("[Pbft::new] quorum: {}, authentication: {:?}, pipeline: {:?}", quorum, authentication, pipeline);
+        let session_keys = SessionKeys::new();
+        let mut pbft = Self {
             keypair,
             addresses: HashMap::new(),
             connected_peers: HashSet::new(),
             queued_events: VecDeque::with_capacity(100), // FIXME
-            state: State::new(),
+            state,
             pre_prepare_sequence: PrePrepareSequence::new(),
             client_replies,
+            request_timers: RequestTimers::new(REQUEST_TIMEOUT),
+            quorum,
+            water_marks,
+            execution_queue: BTreeMap::new(),
+            service,
+            authentication,
+            session_keys,
+            membership,
+            batcher,
+            pipeline,
+            state_transfer: StateTransfer::new(REQUEST_TIMEOUT),
+            retransmissions: Retransmissions::new(RETRANSMISSION_TIMEOUT, MAX_RETRANSMISSIONS),
+            errors: ErrorCounts::default(),
             _marker: std::marker::PhantomData,
+        };
+        pbft.recover();
+        pbft
+    }
+
+    // After a restart, the replica resumes from the state rebuilt from the write-ahead log
+    fn recover(&mut self) {
+        // The primary must not assign a sequence number it has already assigned before the restart
+        let max_n = self.state.get_pre_prepares().iter().map(|p| p.message().sequence_number()).max().unwrap_or(0);
+        if max_n > self.pre_prepare_sequence.value() {
+            self.pre_prepare_sequence.reset(max_n);
+        }
+
+        // The replica was changing views when it stopped. It waits for the new-view message again, and
+        // moves on to the next view if the timer expires.
+        if !self.state.is_view_active() {
+            println!("[Pbft::recover] the replica is changing views. view: {}", self.state.current_view());
+            self.request_timers.start_view_change();
+        }
+
+        // The service state is not in the log. The state up to the stable checkpoint is fetched from
+        // the other replicas once they are connected, and the requests after it are executed again.
+        if self.state.stable_checkpoint().sequence_number() > self.state.last_executed() {
+            eprintln!("[Pbft::recover] the service state up to the stable checkpoint is missing. stable_checkpoint: {}", self.state.stable_checkpoint().sequence_number());
+            self.start_state_transfer(self.state.stable_checkpoint().sequence_number());
+            return;
+        }
+
+        self.execute_committed_in_log();
+    }
+
+    // Executes the requests in the log that are committed but not executed yet
+    fn execute_committed_in_log(&mut self) {
+        let last_executed = self.state.last_executed();
+        let committed: Vec<Commit> = self.state.get_pre_prepares().iter()
+            .map(|p| p.message())
+            .filter(|p| p.sequence_number() > last_executed && self.committed_local(p.view(), p.sequence_number()))
+            .map(|p| Prepare::from(p).into())
+            .collect();
+        println!("[Pbft::execute_committed_in_log] executing the committed requests. committed: {}", committed.len());
+        for commit in committed {
+            self.enqueue_committed_request(commit);
         }
     }
 
@@ -47,6 +158,10 @@ impl<TSubstream> Pbft<TSubstream> {
 
     pub fn add_peer(&mut self, peer_id: &PeerId, address: &Multiaddr) {
         println!("[Pbft::add_peer] {:?}, {:?}", peer_id, address);
+        if !self.membership.contains(peer_id) {
+            println!("[Pbft::add_peer] the peer is not in the membership. peer_id: {:?}", peer_id);
+            return;
+        }
         {
             let mut addresses = match self.addresses.get(peer_id) {
                 Some(addresses) => addresses.clone(),
@@ -62,136 +177,1098 @@ impl<TSubstream> Pbft<TSubstream> {
         });
     }
 
+    fn sign<T: Serialize>(&self, message: T) -> Signed<T> {
+        Signed::new(message, &self.keypair)
+    }
+
+    // The signature is verified against the public keys of the known replicas
+    fn verify_signature<T: Serialize + Display>(&self, signed: &Signed<T>) -> Result<(), PbftError> {
+        signed.verify().map_err(PbftError::InvalidAuthentication)?;
+
+        if !self.membership.is_member(signed.replica()) {
+            return Err(PbftError::InvalidAuthentication(format!("The message is signed by an unknown replica. message: {}", signed)));
+        }
+        Ok(())
+    }
+
+    // A replica cannot send messages on behalf of others, so the signer must be the sender
+    fn verify_sender<T: Serialize + Display>(&self, peer_id: &PeerId, signed: &Signed<T>) -> Result<(), PbftError> {
+        self.verify_signature(signed)?;
+
+        if signed.replica() != &peer_id.to_base58() {
+            return Err(PbftError::InvalidAuthentication(format!("The message is not signed by the sender. message: {}, peer_id: {:?}", signed, peer_id)));
+        }
+        Ok(())
+    }
+
+    // Commit messages are authenticated with MAC authenticators instead of signatures if the
+    // deployment is configured so
+    fn authenticate<T: Serialize>(&self, message: T) -> Envelope<T> {
+        match self.authentication {
+            AuthenticationMode::Signatures => Envelope::Signed(self.sign(message)),
+            AuthenticationMode::Authenticators => Envelope::Authenticated(Authenticated::new(
+                message,
+                &PeerId::from_public_key(self.keypair.public()),
+                self.session_keys.outbound(),
+            )),
+        }
+    }
+
+    // The replica verifies the MAC in the authenticator with the session key it shares with the sender
+    fn verify_authenticator<T: Serialize + Display>(&self, envelope: &Envelope<T>) -> Result<(), PbftError> {
+        match envelope {
+            Envelope::Signed(signed) => self.verify_signature(signed),
+            Envelope::Authenticated(authenticated) => {
+                let replica = envelope.replica().parse::<PeerId>()
+                    .map_err(|e| PbftError::InvalidAuthentication(format!("Invalid replica in the authenticator. message: {}, error: {:?}", envelope, e)))?;
+                let session_key = match self.session_keys.inbound(&replica) {
+                    Some(session_key) => session_key,
+                    None => return Err(PbftError::InvalidAuthentication(format!("No session key is shared with the replica. message: {}", envelope))),
+                };
+                authenticated.verify(&PeerId::from_public_key(self.keypair.public()), session_key).map_err(PbftError::InvalidAuthentication)
+            }
+        }
+    }
+
+    fn verify_authenticated_sender<T: Serialize + Display>(&self, peer_id: &PeerId, envelope: &Envelope<T>) -> Result<(), PbftError> {
+        self.verify_authenticator(envelope)?;
+
+        if envelope.replica() != &peer_id.to_base58() {
+            return Err(PbftError::InvalidAuthentication(format!("The message is not authenticated by the sender. message: {}, peer_id: {:?}", envelope, peer_id)));
+        }
+        Ok(())
+    }
+
+    // The replica stores the session key to authenticate the messages it sends to the peer
+    fn process_new_key(&mut self, peer_id: PeerId, new_key: Signed<NewKey>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &new_key)?;
+
+        if new_key.message().recipient() != &PeerId::from_public_key(self.keypair.public()).to_base58() {
+            return Err(PbftError::InvalidMessage(format!("The NewKey is not sent to the replica. new_key: {}", new_key)));
+        }
+
+        self.session_keys.insert_outbound(peer_id, new_key.into_message().key().clone());
+        Ok(())
+    }
+
     pub fn add_client_request(&mut self, client_request: ClientRequest) {
         println!("[Pbft::add_client_request] client_request: {:?}", client_request);
 
-        // In the pre-prepare phase, the primary assigns a sequence number, n, to the request
+        if let Err(e) = client_request.verify() {
+            eprintln!("[Pbft::add_client_request] the request was discarded as its signature is invalid. error: {}", e);
+            return;
+        }
+
+        // If the request has already been executed, the replica re-sends the last reply to the
+        // client instead of ordering the request again
+        if let Some(reply) = self.state.last_reply(client_request.client_id()) {
+            if client_request.timestamp() == reply.timestamp() {
+                println!("[Pbft::add_client_request] re-sending the last reply to the retransmitted request. reply: {:?}", reply);
+                self.client_replies.write().unwrap().push_back(reply.clone());
+                return;
+            }
+            if client_request.timestamp() < reply.timestamp() {
+                eprintln!("[Pbft::add_client_request] the request was discarded as its timestamp is lower than the last timestamp. last_timestamp: {}", reply.timestamp());
+                return;
+            }
+        }
+
+        // A backup relays the request to the primary and starts a timer for it, so that a primary
+        // which ignores requests gets detected
+        if self.node_type() == NodeType::Backup {
+            let primary = self.primary(self.state.current_view());
+            println!("[Pbft::add_client_request] [forwarding the request to the primary] primary: {:?}", primary);
+            self.request_timers.start(&client_request.digest());
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id: primary,
+                event: PbftHandlerIn::ForwardRequest(client_request),
+            });
+            return;
+        }
+
+        // The primary orders the request together with other pending requests
+        self.batcher.push(client_request);
+    }
+
+    // The pending requests stay queued in the batcher while this returns false
+    fn can_send_pre_prepare(&self) -> bool {
+        if self.node_type() != NodeType::Primary || !self.state.is_view_active() {
+            return false;
+        }
+
+        // A faulty primary could exhaust the space of sequence numbers by selecting a very large one,
+        // so the primary doesn't assign a sequence number above the high water mark
+        if self.water_marks.check(self.state.stable_checkpoint().sequence_number(), self.pre_prepare_sequence.value() + 1).is_err() {
+            return false;
+        }
+
+        !self.pipeline.is_full(self.pre_prepare_sequence.value(), self.state.last_executed())
+    }
+
+    fn send_pre_prepare(&mut self, client_requests: Vec<ClientRequest>) {
+        // In the pre-prepare phase, the primary assigns a sequence number, n, to the batch of requests
         self.pre_prepare_sequence.increment();
-        let pre_prepare = PrePrepare::from(
+        let pre_prepare = self.sign(PrePrepare::from(
             self.state.current_view(),
             self.pre_prepare_sequence.value(),
-            client_request,
-        );
+            client_requests,
+        ));
+
+        println!("[Pbft::send_pre_prepare] [broadcasting the pre_prepare message] pre_prepare: {}", pre_prepare);
+        println!("[Pbft::send_pre_prepare] [broadcasting to the peers] connected_peers: {:?}", self.connected_peers);
+        self.send_tracked(Tracked::PrePrepare(pre_prepare.clone()));
 
-        println!("[Pbft::add_client_request] [broadcasting the pre_prepare message] pre_prepare: {:?}", pre_prepare);
-        println!("[Pbft::add_client_request] [broadcasting to the peers] connected_peers: {:?}", self.connected_peers);
-        if self.connected_peers.is_empty() {
-            panic!("[Pbft::add_client_request] !!! connected_peers is empty !!!");
+        if let Err(e) = self.process_pre_prepare(pre_prepare) {
+            eprintln!("[Pbft::send_pre_prepare] Failed to process the pre-prepare. error: {}", e);
         }
+    }
 
-        for peer_id in self.connected_peers.iter() {
-            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
-                peer_id: peer_id.clone(),
-                event: PbftHandlerIn::PrePrepareRequest(pre_prepare.clone())
-            });
+    // The request has been relayed by a backup. It is not relayed again, to avoid forwarding loops
+    // between replicas that disagree on the primary.
+    fn process_forwarded_request(&mut self, client_request: ClientRequest) -> Result<(), PbftError> {
+        let current_view = self.state.current_view();
+        if self.node_type() != NodeType::Primary || !self.state.is_view_active() {
+            return Err(PbftError::InvalidMessage(format!("The replica is not the primary of the current view. current_view: {}, client_request: {:?}", current_view, client_request)));
         }
 
-        self.process_pre_prepare(pre_prepare).unwrap(); // TODO: error handling
+        self.add_client_request(client_request);
+        Ok(())
     }
 
-    fn process_pre_prepare(&mut self, pre_prepare: PrePrepare) -> Result<(), String> {
-        self.validate_pre_prepare(&pre_prepare)?;
-        self.state.insert_pre_prepare(pre_prepare.clone());
+    fn process_pre_prepare(&mut self, pre_prepare: Signed<PrePrepare>) -> Result<(), PbftError> {
+        self.validate_pre_prepare(pre_prepare.message())?;
+        self.accept_pre_prepare(pre_prepare);
+        Ok(())
+    }
 
+    // The pre-prepare has been validated
+    fn accept_pre_prepare(&mut self, pre_prepare: Signed<PrePrepare>) {
         // If backup replica accepts the message, it enters the prepare phase by multicasting a PREPARE message to
-        // all other replicas and adds both messages to its log.
-        let prepare = Prepare::from(&pre_prepare);
-        self.state.insert_prepare(PeerId::from_public_key(self.keypair.public()), prepare.clone());
+        // all other replicas and adds both messages to its log. The primary doesn't send a prepare, as
+        // its pre-prepare already stands for it.
+        let prepare = Prepare::from(pre_prepare.message());
+        let primary = self.primary(prepare.view());
+        self.state.insert_pre_prepare(pre_prepare);
+        if PeerId::from_public_key(self.keypair.public()) != primary {
+            let signed = self.sign(prepare.clone());
+            self.state.insert_prepare(PeerId::from_public_key(self.keypair.public()), Envelope::Signed(signed.clone()));
+            self.send_tracked(Tracked::Prepare(signed));
+        }
 
-        if self.connected_peers.is_empty() {
-            panic!("[Pbft::process_pre_prepare] !!! Peers not found !!!");
+        // The pre-prepare completes the certificate if no prepares are required, i.e. with a single replica
+        self.send_commit_if_prepared(prepare);
+    }
+
+    // Sends the message to the other replicas and keeps retransmitting it to each of them until it
+    // acknowledges the message. The replicas that are not connected receive it when they reconnect,
+    // unless a checkpoint after it has become stable in the meantime.
+    fn send_tracked(&mut self, message: Tracked) {
+        let local_peer_id = PeerId::from_public_key(self.keypair.public());
+        for peer_id in self.membership.replicas().clone() {
+            if peer_id == local_peer_id {
+                continue;
+            }
+            self.retransmissions.track(peer_id.clone(), message.clone());
+            if self.connected_peers.contains(&peer_id) {
+                let event = self.tracked_to_handler_in(&message);
+                self.queued_events.push_back(NetworkBehaviourAction::SendEvent { peer_id, event });
+            }
         }
+    }
 
-        for peer_id in self.connected_peers.iter() {
+    // Commit messages are authenticated again, as the session keys may have changed since the
+    // message was first sent
+    fn tracked_to_handler_in(&self, message: &Tracked) -> PbftHandlerIn {
+        match message {
+            Tracked::PrePrepare(pre_prepare) => PbftHandlerIn::PrePrepareRequest(pre_prepare.clone()),
+            Tracked::Prepare(prepare) => PbftHandlerIn::PrepareRequest(Envelope::Signed(prepare.clone())),
+            Tracked::Commit(commit) => PbftHandlerIn::CommitRequest(self.authenticate(commit.clone())),
+        }
+    }
+
+    // The pre-prepare has been sent by a peer
+    fn process_received_pre_prepare(&mut self, peer_id: &PeerId, pre_prepare: Signed<PrePrepare>) -> Result<(), PbftError> {
+        self.verify_sender(peer_id, &pre_prepare)?;
+
+        // Only the primary of the view assigns sequence numbers
+        let primary = self.primary(pre_prepare.message().view());
+        if peer_id != &primary || pre_prepare.replica() != &primary.to_base58() {
+            return Err(PbftError::InvalidMessage(format!("The pre-prepare is not sent by the primary of the view. pre_prepare: {}, peer_id: {:?}", pre_prepare, peer_id)));
+        }
+
+        // The primary retransmits a pre-prepare that the replica has already accepted if the
+        // acknowledgement was lost. The replica acknowledges it again and sends its prepare only to
+        // the primary, instead of multicasting the prepare again.
+        if let Some(prepare) = self.own_prepare(pre_prepare.message()) {
+            println!("[Pbft::process_received_pre_prepare] the pre-prepare has already been accepted. pre_prepare: {}", pre_prepare);
             self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
                 peer_id: peer_id.clone(),
-                event: PbftHandlerIn::PrepareRequest(prepare.clone())
-            })
+                event: PbftHandlerIn::PrepareRequest(prepare),
+            });
+            return Ok(());
         }
-        Ok(())
+
+        // The backup starts a timer for each request in the batch, if the timer is not already running
+        for client_request in pre_prepare.message().client_requests() {
+            self.request_timers.start(&client_request.digest());
+        }
+        self.process_pre_prepare(pre_prepare)
+    }
+
+    // The prepare the replica has sent for the pre-prepare, if it has already accepted it
+    fn own_prepare(&self, pre_prepare: &PrePrepare) -> Option<Envelope<Prepare>> {
+        match self.state.get_pre_prepare(pre_prepare) {
+            Some(stored) if stored.digest() == pre_prepare.digest() => {}
+            _ => return None,
+        }
+        self.state.get_prepares(pre_prepare.view(), pre_prepare.sequence_number(), pre_prepare.digest())
+            .and_then(|prepares| prepares.get(&PeerId::from_public_key(self.keypair.public())))
+            .cloned()
+    }
+
+    fn validate_pre_prepare(&self, pre_prepare: &PrePrepare) -> Result<(), PbftError> {
+        // the replica doesn't accept messages other than view-change and new-view while changing views
+        if !self.state.is_view_active() {
+            return Err(PbftError::InvalidMessage(format!("The view {} is not active. pre-prepare: {}", self.state.current_view(), pre_prepare)));
+        }
+
+        self.validate_pre_prepare_in(pre_prepare, self.state.current_view(), self.state.stable_checkpoint().sequence_number())
     }
 
-    fn validate_pre_prepare(&self, pre_prepare: &PrePrepare) -> Result<(), String> {
-        // TODO: the signatures in the request and the pre-prepare message are correct
+    // Validates the pre-prepare against the view _v_ and the low water mark _h_, which are those of
+    // the new view for the pre-prepares in a new-view message
+    fn validate_pre_prepare_in(&self, pre_prepare: &PrePrepare, view: u64, low_water_mark: u64) -> Result<(), PbftError> {
+        // the signatures in the requests are correct
+        for client_request in pre_prepare.client_requests() {
+            client_request.verify().map_err(PbftError::InvalidAuthentication)?;
+        }
 
         // _d_ is the digest for _m_
-        pre_prepare.validate_digest()?;
+        pre_prepare.validate_digest().map_err(PbftError::InvalidMessage)?;
 
         {
             // it is in view _v_
-            let current_view = self.state.current_view();
-            if pre_prepare.view() != current_view {
-                return Err(format!("view number isn't matched. message: {}, state: {}", pre_prepare.view(), current_view));
+            if pre_prepare.view() != view {
+                return Err(PbftError::InvalidMessage(format!("view number isn't matched. message: {}, state: {}", pre_prepare.view(), view)));
             }
 
             // it has not accepted a pre-prepare message for view _v_ and sequence number _n_ containing a different digest
             match self.state.get_pre_prepare(pre_prepare) {
                 Some(stored_pre_prepare) => {
                     if pre_prepare.digest() != stored_pre_prepare.digest() {
-                        return Err(format!("The pre-prepare key has already stored into logs and its digest dont match. message: {}, stored message: {}", pre_prepare, stored_pre_prepare));
+                        return Err(PbftError::InvalidMessage(format!("The pre-prepare key has already stored into logs and its digest dont match. message: {}, stored message: {}", pre_prepare, stored_pre_prepare)));
                     }
                 }
                 None => {}
             }
         }
 
-        // TODO: the sequence number in the pre-prepare message is between a low water mark, _h_, and a high water mark, _H_
+        // the sequence number in the pre-prepare message is between a low water mark, _h_, and a high water mark, _H_
+        self.water_marks.check(low_water_mark, pre_prepare.sequence_number())?;
+
+        Ok(())
+    }
+
+    fn process_prepare(&mut self, peer_id: PeerId, prepare: Envelope<Prepare>) -> Result<(), PbftError> {
+        // Prepare messages are always signed, as they are forwarded in prepared certificates
+        if let Envelope::Authenticated(_) = prepare {
+            return Err(PbftError::InvalidAuthentication(format!("The prepare is not signed. prepare: {}", prepare)));
+        }
+        self.verify_authenticated_sender(&peer_id, &prepare)?;
+        if peer_id == self.primary(prepare.message().view()) {
+            return Err(PbftError::InvalidMessage(format!("The primary doesn't send prepares. prepare: {}", prepare)));
+        }
+        self.validate_prepare(prepare.message())?;
+
+        // A retransmitted prepare is acknowledged again, but doesn't change the log
+        let prepare_message = prepare.message().clone();
+        let duplicate = self.state.get_prepares(prepare_message.view(), prepare_message.sequence_number(), prepare_message.digest())
+            .and_then(|prepares| prepares.get(&peer_id))
+            .is_some();
+        if duplicate {
+            println!("[Pbft::process_prepare] the prepare has already been accepted. prepare: {}", prepare);
+            return Ok(());
+        }
+        self.state.insert_prepare(peer_id, prepare);
 
+        self.send_commit_if_prepared(prepare_message);
         Ok(())
     }
 
-    fn validate_prepare(&self, prepare: &Prepare) -> Result<(), String> {
+    // Once `prepared(m, v, n, i)` becomes true, replica _i_ multicasts a COMMIT message to the other replicas.
+    // The replica's own commit in the log records that the commit has been sent, so the prepares that
+    // arrive after the certificate is complete don't send it again.
+    fn send_commit_if_prepared(&mut self, prepare: Prepare) {
+        let local_peer_id = PeerId::from_public_key(self.keypair.public());
+        if self.state.has_commit(&local_peer_id, prepare.view(), prepare.sequence_number(), prepare.digest()) {
+            return;
+        }
+
+        if self.prepared(prepare.view(), prepare.sequence_number()) {
+            let commit: Commit = prepare.into();
+            self.send_tracked(Tracked::Commit(commit.clone()));
+
+            // The replica inserts its own commit into its log
+            self.state.insert_commit(local_peer_id, commit.clone());
+            if self.committed_local(commit.view(), commit.sequence_number()) {
+                self.enqueue_committed_request(commit);
+            }
+        }
+    }
+
+    fn validate_prepare(&self, prepare: &Prepare) -> Result<(), PbftError> {
+        // the view number in the message is equal to the replica's current view
+        if !self.state.is_view_active() || prepare.view() != self.state.current_view() {
+            return Err(PbftError::InvalidMessage(format!("The view number in the message is NOT equal to the replica's current view. Prepare.view: {}, current_view: {}", prepare.view(), self.state.current_view())));
+        }
+
+        self.water_marks.check(self.state.stable_checkpoint().sequence_number(), prepare.sequence_number())?;
+
         // The replicas verify whether the prepares match the pre-prepare by checking that they have the
         // same view, sequence number, and digest.
         if let Some(pre_prepare) = self.state.get_pre_prepare_by_key(prepare.view(), prepare.sequence_number()) {
             if pre_prepare.digest() == prepare.digest() {
                 return Ok(());
             }
-            return Err(format!("the Prepare request doesn't match with the PrePrepare. prepare: {}, pre-prepare: {}", prepare, pre_prepare))
+            return Err(PbftError::InvalidMessage(format!("the Prepare request doesn't match with the PrePrepare. prepare: {}, pre-prepare: {}", prepare, pre_prepare)))
         }
-        Err(format!("No PrePrepare that matches with the Prepare. prepare: {}", prepare))
+        Err(PbftError::InvalidMessage(format!("No PrePrepare that matches with the Prepare. prepare: {}", prepare)))
     }
 
+    // `prepared(m, v, n, i)` is true if and only if replica _i_ has inserted in its log: the request _m_,
+    // a pre-prepare for _m_ in view _v_ with sequence number _n_, and 2f prepares from different
+    // backups that match the pre-prepare.
     fn prepared(&self, view: u64, sequence_number: u64) -> bool {
-        // 2f prepares from different backups that match the pre-prepare.
-        let len = self.state.prepare_len(view, sequence_number);
+        let pre_prepare = match self.state.get_pre_prepare_by_key(view, sequence_number) {
+            Some(pre_prepare) => pre_prepare,
+            None => return false,
+        };
+        let len = self.backup_prepares(view, sequence_number, pre_prepare.digest()).len();
         println!("[Pbft::prepared] prepare_len: {}", len);
-        len >= 1 // TODO
+        len >= self.quorum.prepare()
+    }
+
+    // The prepares in the log that match the digest, except the one from the primary of the view. A
+    // faulty primary could otherwise complete a certificate with a single backup for each of two
+    // conflicting pre-prepares.
+    fn backup_prepares(&self, view: u64, sequence_number: u64, digest: &str) -> Vec<&Envelope<Prepare>> {
+        let primary = self.primary(view);
+        match self.state.get_prepares(view, sequence_number, digest) {
+            Some(prepares) => prepares.iter()
+                .filter(|(peer_id, _)| **peer_id != primary)
+                .map(|(_, prepare)| prepare)
+                .collect(),
+            None => Vec::new(),
+        }
     }
 
-    fn validate_commit(&self, commit: &Commit) -> Result<(), String> {
-        // TODO: properly signed
+    fn process_commit(&mut self, peer_id: PeerId, commit: Envelope<Commit>) -> Result<(), PbftError> {
+        self.verify_authenticated_sender(&peer_id, &commit)?;
+        let commit = commit.into_message();
+        self.validate_commit(&commit)?;
 
+        // Replicas accept commit messages and insert them in their log
+        self.state.insert_commit(peer_id, commit.clone());
+
+        // Each replica _i_ executes the operation requested by _m_ after `committed-local(m, v, n, i)` is true
+        if self.committed_local(commit.view(), commit.sequence_number()) {
+            self.enqueue_committed_request(commit);
+        }
+        Ok(())
+    }
+
+    fn validate_commit(&self, commit: &Commit) -> Result<(), PbftError> {
         // the view number in the message is equal to the replica's current view
-        if commit.view() != self.state.current_view() {
-            return Err(format!("The view number in the message is NOT equal to the replica's current view. Commit.view: {}, current_view: {}", commit.view(), self.state.current_view()));
+        if !self.state.is_view_active() || commit.view() != self.state.current_view() {
+            return Err(PbftError::InvalidMessage(format!("The view number in the message is NOT equal to the replica's current view. Commit.view: {}, current_view: {}", commit.view(), self.state.current_view())));
         }
 
-        // TODO: the sequence number is between h and H
+        // the sequence number is between h and H
+        self.water_marks.check(self.state.stable_checkpoint().sequence_number(), commit.sequence_number())?;
 
         Ok(())
     }
 
+    // The number of commits in the log that match the pre-prepare for the request
+    fn matching_commit_len(&self, view: u64, sequence_number: u64) -> usize {
+        match self.state.get_pre_prepare_by_key(view, sequence_number) {
+            Some(pre_prepare) => self.state.commit_len(view, sequence_number, pre_prepare.digest()),
+            None => 0,
+        }
+    }
+
     // `committed(m, v, n)` is true if and only if `prepared(m, v, n, i)` is true for all _i_ in
     // some set of `f + 1` non-faulty replicas.
     #[allow(dead_code)]
     fn committed(&self, view: u64, sequence_number: u64) -> bool {
-        let len = self.state.commit_len(view);
+        let len = self.matching_commit_len(view, sequence_number);
         let prepared = self.prepared(view, sequence_number);
 
         println!("[Pbft::committed] commit_len: {}, prepared: {}", len, prepared);
-        prepared && len >= 1 // TODO: f + 1
+        prepared && len >= self.quorum.weak()
     }
 
     // `committed-local(m, v, n, i)` is true if and only if `prepared(m, v, n, i)` is true and _i_
     // has accepted `2f + 1` commits (possibly including its own) from different replicas that match
     // the pre-prepare for _m_.
     fn committed_local(&self, view: u64, sequence_number: u64) -> bool {
-        let len = self.state.commit_len(view);
+        let len = self.matching_commit_len(view, sequence_number);
         let prepared = self.prepared(view, sequence_number);
 
         println!("[Pbft::committed_local] commit_len: {}, prepared: {}", len, prepared);
-        prepared && len >= 1 // TODO: 2f + 1
+        prepared && len >= self.quorum.strong()
+    }
+
+    // The primary of a view is replica _p_ such that _p = v mod |R|_
+    fn primary(&self, view: u64) -> PeerId {
+        self.membership.primary(view).clone()
+    }
+
+    // The role of the replica is derived from the current view, so it changes as the primary rotates
+    pub fn node_type(&self) -> NodeType {
+        if self.primary(self.state.current_view()) == PeerId::from_public_key(self.keypair.public()) {
+            NodeType::Primary
+        } else {
+            NodeType::Backup
+        }
+    }
+
+    pub fn error_counts(&self) -> &ErrorCounts {
+        &self.errors
+    }
+
+    // `P` contains a prepared certificate for each request that prepared at the replica
+    fn prepared_certificates(&self) -> Vec<PreparedCertificate> {
+        self.state.get_pre_prepares().into_iter()
+            .filter(|signed| self.prepared(signed.message().view(), signed.message().sequence_number()))
+            .map(|signed| {
+                let pre_prepare = signed.message();
+                let prepares = self.backup_prepares(pre_prepare.view(), pre_prepare.sequence_number(), pre_prepare.digest())
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                PreparedCertificate::new(signed.clone(), prepares)
+            })
+            .collect()
+    }
+
+    // A backup moves into view _v_ + 1 and multicasts a VIEW-CHANGE message to all replicas
+    fn start_view_change(&mut self, new_view: u64) {
+        println!("[Pbft::start_view_change] new_view: {}", new_view);
+        let local_peer_id = PeerId::from_public_key(self.keypair.public());
+        let stable_checkpoint = self.state.stable_checkpoint();
+        let view_change = self.sign(ViewChange::new(
+            new_view,
+            stable_checkpoint.sequence_number(),
+            stable_checkpoint.proof().clone(),
+            self.prepared_certificates(),
+        ));
+
+        self.state.start_view_change(new_view);
+        self.state.insert_view_change(local_peer_id, view_change.clone());
+        self.request_timers.start_view_change();
+
+        for peer_id in self.connected_peers.iter() {
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id: peer_id.clone(),
+                event: PbftHandlerIn::ViewChangeRequest(view_change.clone())
+            });
+        }
+
+        self.send_new_view_if_primary(new_view);
+    }
+
+    fn process_view_change(&mut self, peer_id: PeerId, view_change: Signed<ViewChange>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &view_change)?;
+        self.validate_view_change(view_change.message())?;
+        let new_view = view_change.message().new_view();
+        self.state.insert_view_change(peer_id, view_change);
+
+        // If a replica receives a set of `f + 1` valid VIEW-CHANGE messages from other replicas for
+        // views greater than its current view, it sends a VIEW-CHANGE message for the smallest view
+        // in the set, even if its timer has not expired.
+        let current_view = self.state.current_view();
+        if let Some(smallest_view) = self.state.smallest_view_change_greater_than(current_view) {
+            if self.state.view_change_len_greater_than(current_view) >= self.quorum.weak() {
+                self.start_view_change(smallest_view);
+            }
+        }
+
+        self.send_new_view_if_primary(new_view);
+        Ok(())
+    }
+
+    fn validate_view_change(&self, view_change: &ViewChange) -> Result<(), PbftError> {
+        let current_view = self.state.current_view();
+        if view_change.new_view() < current_view || (view_change.new_view() == current_view && self.state.is_view_active()) {
+            return Err(PbftError::InvalidMessage(format!("The ViewChange is for a stale view. view_change: {}, current_view: {}", view_change, current_view)));
+        }
+        if view_change.new_view() > current_view + MAX_VIEW_CHANGE_DISTANCE {
+            return Err(PbftError::InvalidMessage(format!("The ViewChange is too far ahead of the current view. view_change: {}, current_view: {}", view_change, current_view)));
+        }
+
+        self.validate_view_change_proofs(view_change)
+    }
+
+    fn validate_view_change_proofs(&self, view_change: &ViewChange) -> Result<(), PbftError> {
+        // `C` contains 2f + 1 checkpoint messages with the same digest for the last stable checkpoint
+        if view_change.last_stable_checkpoint() > 0 {
+            self.validate_checkpoint_proof(view_change.last_stable_checkpoint(), view_change.checkpoint_proof())?;
+        }
+
+        // Each set in `P` contains a valid pre-prepare message and 2f matching prepare messages from
+        // different backups
+        for certificate in view_change.prepared_certificates() {
+            self.verify_signature(certificate.pre_prepare())?;
+            let pre_prepare = certificate.pre_prepare().message();
+            let primary = self.primary(pre_prepare.view()).to_base58();
+            if certificate.pre_prepare().replica() != &primary {
+                return Err(PbftError::InvalidMessage(format!("The pre-prepare in the prepared certificate is not signed by the primary of its view. view_change: {}", view_change)));
+            }
+            if pre_prepare.view() >= view_change.new_view() {
+                return Err(PbftError::InvalidMessage(format!("The prepared certificate is not for a previous view. view_change: {}", view_change)));
+            }
+            pre_prepare.validate_digest().map_err(PbftError::InvalidMessage)?;
+
+            let mut replicas = HashSet::new();
+            for envelope in certificate.prepares() {
+                let signed = match envelope {
+                    Envelope::Signed(signed) => signed,
+                    Envelope::Authenticated(_) => return Err(PbftError::InvalidAuthentication(format!("The prepare in the prepared certificate is not signed. view_change: {}", view_change))),
+                };
+                self.verify_signature(signed)?;
+                let prepare = envelope.message();
+                if envelope.replica() != &primary
+                    && prepare.view() == pre_prepare.view()
+                    && prepare.sequence_number() == pre_prepare.sequence_number()
+                    && prepare.digest() == pre_prepare.digest() {
+                    replicas.insert(envelope.replica());
+                }
+            }
+            if replicas.len() < self.quorum.prepare() {
+                return Err(PbftError::InvalidMessage(format!("The prepared certificate doesn't have enough matching prepares. view_change: {}", view_change)));
+            }
+        }
+
+        Ok(())
+    }
+
+    // When the primary _p_ of view _v_ + 1 receives 2f valid VIEW-CHANGE messages for view _v_ + 1
+    // from other replicas, it multicasts a NEW-VIEW message to all other replicas.
+    fn send_new_view_if_primary(&mut self, new_view: u64) {
+        let local_peer_id = PeerId::from_public_key(self.keypair.public());
+        if self.primary(new_view) != local_peer_id
+            || self.state.current_view() != new_view
+            || self.state.is_view_active() {
+            return;
+        }
+
+        // 2f valid VIEW-CHANGE messages from other replicas and its own
+        if self.state.view_change_len(new_view) < self.quorum.strong() {
+            return;
+        }
+
+        let view_changes = self.state.get_view_changes(new_view);
+        let pre_prepares = NewView::compute_pre_prepares(new_view, &view_changes).into_iter()
+            .map(|pre_prepare| self.sign(pre_prepare))
+            .collect();
+        let new_view = self.sign(NewView::new(new_view, view_changes, pre_prepares));
+        println!("[Pbft::send_new_view_if_primary] [broadcasting the new_view message] new_view: {}", new_view);
+        for peer_id in self.connected_peers.iter() {
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id: peer_id.clone(),
+                event: PbftHandlerIn::NewViewRequest(new_view.clone())
+            });
+        }
+
+        self.enter_new_view(new_view.message());
+    }
+
+    fn process_new_view(&mut self, peer_id: PeerId, new_view: Signed<NewView>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &new_view)?;
+        self.validate_new_view(&peer_id, new_view.message())?;
+        self.enter_new_view(new_view.message());
+        Ok(())
+    }
+
+    fn validate_new_view(&self, peer_id: &PeerId, new_view: &NewView) -> Result<(), PbftError> {
+        if &self.primary(new_view.view()) != peer_id {
+            return Err(PbftError::InvalidMessage(format!("The NewView was not sent by the primary of the view. new_view: {}, peer_id: {:?}", new_view, peer_id)));
+        }
+
+        let current_view = self.state.current_view();
+        if new_view.view() < current_view || (new_view.view() == current_view && self.state.is_view_active()) {
+            return Err(PbftError::InvalidMessage(format!("The NewView is for a stale view. new_view: {}, current_view: {}", new_view, current_view)));
+        }
+
+        // `V` contains valid view-change messages for view _v_ + 1 signed by 2f + 1 different replicas
+        let mut replicas = HashSet::new();
+        for signed in new_view.view_changes() {
+            self.verify_signature(signed)?;
+            if signed.message().new_view() != new_view.view() {
+                return Err(PbftError::InvalidMessage(format!("The NewView contains a ViewChange for another view. new_view: {}", new_view)));
+            }
+            self.validate_view_change_proofs(signed.message())?;
+            replicas.insert(signed.replica());
+        }
+        if replicas.len() < self.quorum.strong() {
+            return Err(PbftError::InvalidMessage(format!("The NewView doesn't have enough ViewChange messages. new_view: {}", new_view)));
+        }
+
+        // Every pre-prepare in `O` must be signed by the new primary and valid in the new view, whose
+        // low water mark is the checkpoint _min-s_ unless the replica already has a later one. They
+        // are all validated here so that the replica doesn't enter the new view with part of `O`.
+        let primary = self.primary(new_view.view()).to_base58();
+        let low_water_mark = std::cmp::max(NewView::min_s(new_view.view_changes()), self.state.stable_checkpoint().sequence_number());
+        for signed in new_view.pre_prepares() {
+            self.verify_signature(signed)?;
+            if signed.replica() != &primary {
+                return Err(PbftError::InvalidMessage(format!("The pre-prepare in the NewView is not signed by the primary. pre_prepare: {}", signed)));
+            }
+            self.validate_pre_prepare_in(signed.message(), new_view.view(), low_water_mark)?;
+        }
+
+        // The backup verifies that `O` is correct by performing a computation similar to the one used
+        // by the primary to create `O`
+        let expected = NewView::compute_pre_prepares(new_view.view(), new_view.view_changes());
+        let matched = expected.len() == new_view.pre_prepares().len()
+            && expected.iter().zip(new_view.pre_prepares().iter()).all(|(e, p)| {
+                e.sequence_number() == p.message().sequence_number() && e.digest() == p.message().digest()
+            });
+        if !matched {
+            return Err(PbftError::InvalidMessage(format!("The pre-prepares in the NewView are not correct. new_view: {}", new_view)));
+        }
+
+        Ok(())
+    }
+
+    // The replica adds the new information to its log and enters view _v_ + 1, sending a prepare for
+    // each message in `O`. The new-view message has been validated.
+    fn enter_new_view(&mut self, new_view: &NewView) {
+        // The replica adds the checkpoint with sequence number _min-s_ to its log
+        let min_s = NewView::min_s(new_view.view_changes());
+        if min_s > self.state.stable_checkpoint().sequence_number() {
+            let proof = new_view.view_changes().iter()
+                .map(|v| v.message())
+                .find(|v| v.last_stable_checkpoint() == min_s)
+                .map(|v| v.checkpoint_proof().clone())
+                .unwrap_or_default();
+
+            match proof.first().map(|checkpoint| checkpoint.message().digest().clone()) {
+                Some(digest) if self.state.is_executed(min_s) => {
+                    self.state.update_stable_checkpoint(min_s, digest, proof);
+                }
+                _ => {
+                    eprintln!("[Pbft::enter_new_view] the replica is missing requests up to the checkpoint. min_s: {}", min_s);
+                    self.start_state_transfer(min_s);
+                }
+            }
+        }
+
+        self.state.install_view(new_view.view());
+        self.request_timers.complete_view_change();
+        let primary = self.primary(new_view.view());
+        println!("[Pbft::enter_new_view] view: {}, primary: {:?} (replica id: {:?}), node_type: {}", new_view.view(), primary, self.membership.replica_id(&primary), self.node_type());
+        let is_primary = self.primary(new_view.view()) == PeerId::from_public_key(self.keypair.public());
+
+        let max_s = new_view.pre_prepares().iter().map(|p| p.message().sequence_number()).max().unwrap_or(0);
+        if max_s > self.pre_prepare_sequence.value() {
+            self.pre_prepare_sequence.reset(max_s);
+        }
+
+        for pre_prepare in new_view.pre_prepares() {
+            self.accept_pre_prepare(pre_prepare.clone());
+            if !is_primary {
+                for client_request in pre_prepare.message().client_requests() {
+                    self.request_timers.start(&client_request.digest());
+                }
+            }
+        }
+
+        // The requests the replica batched as the primary of an earlier view and that are not
+        // re-issued in `O` are handed to the primary of the new view, or ordered again if the replica
+        // is still the primary
+        let reissued: HashSet<String> = new_view.pre_prepares().iter()
+            .flat_map(|p| p.message().client_requests().iter().map(|r| r.digest()))
+            .collect();
+        for client_request in self.batcher.drain() {
+            if !reissued.contains(&client_request.digest()) {
+                self.add_client_request(client_request);
+            }
+        }
+    }
+
+    // Each replica _i_ executes the operation requested by _m_ after `committed-local(m, v, n, i)` is true
+    // and _i_'s state reflects the sequential execution of all requests with lower sequence numbers.
+    // This ensures that all non-faulty replicas execute requests in the same order.
+    fn enqueue_committed_request(&mut self, commit: Commit) {
+        if self.state.is_executed(commit.sequence_number()) {
+            return;
+        }
+        println!("[Pbft::enqueue_committed_request] sequence_number: {}, last_executed: {}", commit.sequence_number(), self.state.last_executed());
+        self.execution_queue.insert(commit.sequence_number(), commit);
+
+        while let Some(commit) = self.execution_queue.remove(&(self.state.last_executed() + 1)) {
+            self.execute(&commit);
+        }
+    }
+
+    fn execute(&mut self, commit: &Commit) {
+        let pre_prepare = match self.state.get_pre_prepare_by_key(commit.view(), commit.sequence_number()) {
+            Some(pre_prepare) => pre_prepare.clone(),
+            None => {
+                eprintln!("[Pbft::execute] the pre-prepare for the commit is not found. commit: {}", commit);
+                return;
+            }
+        };
+        if pre_prepare.is_null() {
+            println!("[Pbft::execute] the null request has been executed");
+        }
+
+        // The requests in a batch are executed in the order they appear in the pre-prepare message
+        for client_request in pre_prepare.client_requests() {
+            // The backup stops the timer when it is no longer waiting to execute the request
+            self.request_timers.stop(&client_request.digest());
+            println!("[Pbft::execute] client_message: {:?}", client_request);
+
+            // Discard requests whose timestamp is lower than the timestamp in the last reply this node sent to the client to guarantee exactly-once semantics.
+            let last_timestamp = self.state.last_timestamp(client_request.client_id());
+            if client_request.timestamp() <= last_timestamp {
+                eprintln!(
+                    "[Pbft::execute] the request was discarded as its timestamp is lower than the last timestamp. client_id: {}, last_timestamp: {:?}",
+                    client_request.client_id(),
+                    last_timestamp
+                );
+            } else {
+                let result = self.service.execute(&client_request.operation());
+                println!("[Pbft::execute] the operation has been executed: {:?}, result: {:?}", client_request.operation(), result);
+
+                // After executing the requested operation, replicas send a reply to the client.
+                let reply = ClientReply::new(
+                    PeerId::from_public_key(self.keypair.public()),
+                    client_request,
+                    commit,
+                    result,
+                );
+                println!("[Pbft::execute] reply: {:?}", reply);
+                self.state.update_last_reply(reply.clone());
+                self.client_replies.write().unwrap().push_back(reply);
+            }
+        }
+
+        self.state.record_execution(commit.sequence_number());
+
+        if commit.sequence_number() % CHECKPOINT_PERIOD == 0 {
+            self.send_checkpoint(commit.sequence_number());
+        }
+    }
+
+    // Replica _i_ produces a checkpoint and multicasts a CHECKPOINT message to the other replicas
+    fn send_checkpoint(&mut self, sequence_number: u64) {
+        let local_peer_id = PeerId::from_public_key(self.keypair.public());
+        let replies = self.state.cached_replies();
+        let partitions = self.service.partitions();
+        let root = PartitionTree::from_partitions(&partitions).root();
+        let checkpoint = self.sign(Checkpoint::new(sequence_number, checkpoint_digest(&root, &replies)));
+        self.state.insert_checkpoint_state(CheckpointState::new(sequence_number, partitions, replies));
+        println!("[Pbft::send_checkpoint] [broadcasting the checkpoint message] checkpoint: {}", checkpoint);
+        println!("[Pbft::send_checkpoint] rejected messages: {}", self.error_counts());
+
+        self.state.insert_checkpoint(local_peer_id, checkpoint.clone());
+        for peer_id in self.connected_peers.iter() {
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id: peer_id.clone(),
+                event: PbftHandlerIn::CheckpointRequest(checkpoint.clone())
+            });
+        }
+
+        self.update_stable_checkpoint(sequence_number, checkpoint.message().digest());
+    }
+
+    fn process_checkpoint(&mut self, peer_id: PeerId, checkpoint: Signed<Checkpoint>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &checkpoint)?;
+
+        if checkpoint.message().sequence_number() <= self.state.stable_checkpoint().sequence_number() {
+            println!("[Pbft::process_checkpoint] the checkpoint is older than the stable checkpoint. checkpoint: {}", checkpoint);
+            return Ok(());
+        }
+
+        self.state.insert_checkpoint(peer_id, checkpoint.clone());
+        self.update_stable_checkpoint(checkpoint.message().sequence_number(), checkpoint.message().digest());
+        Ok(())
+    }
+
+    // Each replica collects checkpoint messages in its log until it has 2f + 1 of them for sequence
+    // number _n_ with the same digest _d_ signed by different replicas (including possibly its own).
+    // These 2f + 1 messages are the proof of correctness for the checkpoint.
+    fn update_stable_checkpoint(&mut self, sequence_number: u64, digest: &str) {
+        if sequence_number <= self.state.stable_checkpoint().sequence_number()
+            || self.state.checkpoint_len(sequence_number, digest) < self.quorum.strong() {
+            return;
+        }
+
+        // The replica has not executed the requests up to the checkpoint yet. The checkpoint becomes
+        // stable once it executes them and produces its own checkpoint, unless the other replicas have
+        // moved past the requests this replica is waiting for and discarded the messages for them.
+        // The replica falls back to state transfer if the execution doesn't reach the checkpoint in
+        // time, e.g. as the prepares or commits it is missing are no longer retransmitted.
+        if !self.state.is_executed(sequence_number) {
+            if self.executable_from_log(sequence_number) {
+                println!("[Pbft::update_stable_checkpoint] the replica is executing the requests up to the checkpoint. sequence_number: {}, last_executed: {}", sequence_number, self.state.last_executed());
+                self.state_transfer.catch_up(sequence_number);
+            } else {
+                eprintln!("[Pbft::update_stable_checkpoint] the replica is missing requests up to the checkpoint. sequence_number: {}, last_executed: {}", sequence_number, self.state.last_executed());
+                self.start_state_transfer(sequence_number);
+            }
+            return;
+        }
+
+        let proof = self.state.get_checkpoints(sequence_number, digest);
+        self.state.update_stable_checkpoint(sequence_number, digest.to_owned(), proof);
+    }
+
+    // The requests up to the checkpoint can still be executed from the log if the replica has accepted
+    // the pre-prepares for all of them, and has executed the requests up to the previous checkpoint,
+    // i.e. it is not below the low water mark of the other replicas. Otherwise the prepares and
+    // commits it is missing may no longer be retransmitted.
+    fn executable_from_log(&self, sequence_number: u64) -> bool {
+        let last_executed = self.state.last_executed();
+        last_executed + CHECKPOINT_PERIOD >= sequence_number
+            && (last_executed + 1..=sequence_number).all(|n| self.state.has_pre_prepare(n))
+    }
+
+    fn validate_checkpoint_proof(&self, sequence_number: u64, proof: &[Signed<Checkpoint>]) -> Result<(), PbftError> {
+        let digest = match proof.first() {
+            Some(checkpoint) => checkpoint.message().digest(),
+            None => return Err(PbftError::InvalidMessage(format!("The checkpoint proof is empty. sequence_number: {}", sequence_number))),
+        };
+
+        let mut replicas = HashSet::new();
+        for signed in proof {
+            self.verify_signature(signed)?;
+            let checkpoint = signed.message();
+            if checkpoint.sequence_number() == sequence_number && checkpoint.digest() == digest {
+                replicas.insert(signed.replica());
+            }
+        }
+        if replicas.len() < self.quorum.strong() {
+            return Err(PbftError::InvalidMessage(format!("The checkpoint proof doesn't have enough matching checkpoints. sequence_number: {}", sequence_number)));
+        }
+        Ok(())
+    }
+
+    // Fetches the state from the other replicas to catch up to the checkpoint with the sequence number
+    fn start_state_transfer(&mut self, sequence_number: u64) {
+        if !self.state_transfer.start(sequence_number) {
+            return;
+        }
+
+        let fetch_state = self.sign(FetchState::new(self.state.last_executed()));
+        println!("[Pbft::start_state_transfer] [broadcasting the fetch-state message] fetch_state: {}", fetch_state);
+        for peer_id in self.connected_peers.iter() {
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id: peer_id.clone(),
+                event: PbftHandlerIn::FetchStateRequest(fetch_state.clone()),
+            });
+        }
+    }
+
+    // The replica sends its latest stable checkpoint, if the requester has not reached it, and the
+    // requests it has executed after the last request the requester has executed
+    fn process_fetch_state(&mut self, peer_id: PeerId, fetch_state: Signed<FetchState>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &fetch_state)?;
+        let last_executed = fetch_state.message().last_executed();
+
+        let stable_checkpoint = self.state.stable_checkpoint();
+        let checkpoint = if stable_checkpoint.sequence_number() > last_executed {
+            self.state.checkpoint_state(stable_checkpoint.sequence_number())
+                .map(|c| c.summary(stable_checkpoint.proof().clone()))
+        } else {
+            None
+        };
+
+        let mut committed: Vec<Signed<PrePrepare>> = self.state.get_pre_prepares().into_iter()
+            .filter(|p| {
+                let n = p.message().sequence_number();
+                n > last_executed && self.state.is_executed(n) && self.committed_local(p.message().view(), n)
+            })
+            .cloned()
+            .collect();
+        committed.sort_by_key(|p| p.message().sequence_number());
+
+        let state_snapshot = self.sign(StateSnapshot::new(checkpoint, committed));
+        println!("[Pbft::process_fetch_state] state_snapshot: {}", state_snapshot);
+        self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: PbftHandlerIn::StateSnapshotRequest(state_snapshot),
+        });
+        Ok(())
+    }
+
+    fn process_state_snapshot(&mut self, peer_id: PeerId, state_snapshot: Signed<StateSnapshot>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &state_snapshot)?;
+        if !self.state_transfer.is_in_progress() {
+            return Err(PbftError::InvalidMessage(format!("The replica is not fetching the state. state_snapshot: {}", state_snapshot)));
+        }
+
+        let (checkpoint, committed) = state_snapshot.into_message().into_parts();
+        if let Some(summary) = checkpoint {
+            let pending = match self.state_transfer.pending_checkpoint() {
+                Some(n) => summary.sequence_number() <= n,
+                None => false,
+            };
+            if summary.sequence_number() > self.state.last_executed() && !pending {
+                self.fetch_checkpoint(peer_id.clone(), summary)?;
+            }
+        }
+
+        for pre_prepare in committed {
+            match self.validate_transferred_pre_prepare(&pre_prepare) {
+                Ok(()) => self.state_transfer.insert_committed(peer_id.clone(), pre_prepare),
+                Err(e) => eprintln!("[Pbft::process_state_snapshot] the pre-prepare was discarded. error: {}", e),
+            }
+        }
+
+        while let Some(pre_prepare) = self.state_transfer.take_committed(self.state.last_executed() + 1, self.quorum.weak()) {
+            let commit: Commit = Prepare::from(pre_prepare.message()).into();
+            self.state.insert_pre_prepare(pre_prepare);
+            self.enqueue_committed_request(commit);
+        }
+        Ok(())
+    }
+
+    // The digests of the partitions are verified against the checkpoint proof, and the partitions
+    // whose digests differ from the local ones are fetched from the replica that sent them
+    fn fetch_checkpoint(&mut self, peer_id: PeerId, summary: CheckpointSummary) -> Result<(), PbftError> {
+        let sequence_number = summary.sequence_number();
+        self.validate_checkpoint_proof(sequence_number, summary.proof())?;
+        let tree = PartitionTree::new(summary.partition_digests().clone());
+        if &checkpoint_digest(&tree.root(), summary.replies()) != summary.proof()[0].message().digest() {
+            return Err(PbftError::InvalidMessage(format!("The partition digests don't match the checkpoint. sequence_number: {}", sequence_number)));
+        }
+
+        let missing = tree.differing_leaves(&PartitionTree::from_partitions(&self.service.partitions()));
+        println!("[Pbft::fetch_checkpoint] sequence_number: {}, partitions: {}, missing: {:?}", sequence_number, tree.leaves().len(), missing);
+        self.state_transfer.fetch_partitions(summary, peer_id.clone(), missing.clone());
+        if missing.is_empty() {
+            return self.install_fetched_checkpoint();
+        }
+
+        self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: PbftHandlerIn::FetchPartitionsRequest(self.sign(FetchPartitions::new(sequence_number, missing))),
+        });
+        Ok(())
+    }
+
+    fn process_fetch_partitions(&mut self, peer_id: PeerId, fetch_partitions: Signed<FetchPartitions>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &fetch_partitions)?;
+        let sequence_number = fetch_partitions.message().sequence_number();
+
+        let checkpoint_state = self.state.checkpoint_state(sequence_number)
+            .ok_or_else(|| PbftError::InvalidMessage(format!("The state at the checkpoint is not available. sequence_number: {}", sequence_number)))?;
+        let partitions = fetch_partitions.message().partitions().iter()
+            .map(|i| checkpoint_state.partition(*i).map(|p| (*i, p.clone())).ok_or_else(|| PbftError::InvalidMessage(format!("Invalid partition: {}", i))))
+            .collect::<Result<Vec<_>, PbftError>>()?;
+
+        let partitions = self.sign(Partitions::new(sequence_number, partitions));
+        println!("[Pbft::process_fetch_partitions] partitions: {}", partitions);
+        self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: PbftHandlerIn::PartitionsRequest(partitions),
+        });
+        Ok(())
+    }
+
+    fn process_partitions(&mut self, peer_id: PeerId, partitions: Signed<Partitions>) -> Result<(), PbftError> {
+        self.verify_sender(&peer_id, &partitions)?;
+        let sequence_number = partitions.message().sequence_number();
+
+        for (index, partition) in partitions.into_message().into_partitions() {
+            self.state_transfer.insert_partition(&peer_id, sequence_number, index, partition).map_err(PbftError::InvalidMessage)?;
+        }
+        self.install_fetched_checkpoint()
+    }
+
+    // The fetched partitions are installed via the partition hook of the service only if the digest of
+    // the resulting state matches the digest in the checkpoint proof
+    fn install_fetched_checkpoint(&mut self) -> Result<(), PbftError> {
+        let (summary, partitions) = match self.state_transfer.take_fetched_checkpoint() {
+            Some(fetched) => fetched,
+            None => return Ok(()),
+        };
+        let sequence_number = summary.sequence_number();
+        if sequence_number <= self.state.last_executed() {
+            println!("[Pbft::install_fetched_checkpoint] the replica has already executed the requests up to the checkpoint. sequence_number: {}", sequence_number);
+            return Ok(());
+        }
+        let digest = summary.proof()[0].message().digest().clone();
+
+        let current = self.service.partitions();
+        let rollback: Vec<(usize, Vec<u8>)> = partitions.iter()
+            .filter_map(|(i, _)| current.get(*i).map(|p| (*i, p.clone())))
+            .collect();
+        self.service.restore_partitions(&partitions).map_err(PbftError::InvalidMessage)?;
+        let root = PartitionTree::from_partitions(&self.service.partitions()).root();
+        if checkpoint_digest(&root, summary.replies()) != digest {
+            self.service.restore_partitions(&rollback).map_err(PbftError::InvalidMessage)?;
+            return Err(PbftError::InvalidMessage(format!("The digest of the transferred state doesn't match the checkpoint. sequence_number: {}", sequence_number)));
+        }
+
+        let local_peer_id = PeerId::from_public_key(self.keypair.public());
+        let replies = summary.replies().iter()
+            .map(|reply| ClientReply::from_cached(local_peer_id.clone(), reply.clone()))
+            .collect();
+        self.state.restore_checkpoint(sequence_number, replies);
+        self.execution_queue = self.execution_queue.split_off(&(sequence_number + 1));
+        if sequence_number > self.pre_prepare_sequence.value() {
+            self.pre_prepare_sequence.reset(sequence_number);
+        }
+
+        self.state.insert_checkpoint_state(CheckpointState::new(sequence_number, self.service.partitions(), summary.replies().clone()));
+        if sequence_number > self.state.stable_checkpoint().sequence_number() {
+            self.state.update_stable_checkpoint(sequence_number, digest, summary.proof().clone());
+        }
+        println!("[Pbft::install_fetched_checkpoint] the state at the checkpoint has been installed. sequence_number: {}, fetched partitions: {}", sequence_number, partitions.len());
+
+        // The requests after the checkpoint may already be committed in the log
+        self.execute_committed_in_log();
+        Ok(())
+    }
+
+    // The transferred pre-prepare is accepted only if it is signed by a replica and the digest covers
+    // the requests in it
+    fn validate_transferred_pre_prepare(&self, pre_prepare: &Signed<PrePrepare>) -> Result<(), PbftError> {
+        self.verify_signature(pre_prepare)?;
+        pre_prepare.message().validate_digest().map_err(PbftError::InvalidMessage)?;
+        for client_request in pre_prepare.message().client_requests() {
+            client_request.verify().map_err(PbftError::InvalidAuthentication)?;
+        }
+        Ok(())
+    }
+
+    // Answers the request of the peer with "OK", or with the error that the request has been rejected with
+    fn respond(
+        &mut self,
+        peer_id: PeerId,
+        event_name: &str,
+        result: Result<(), PbftError>,
+        response: fn(Vec<u8>, ConnectionId) -> PbftHandlerIn,
+        connection_id: ConnectionId,
+    ) {
+        let response_message = match result {
+            Ok(()) => "OK".to_owned(),
+            Err(e) => {
+                eprintln!("[Pbft::respond] [PbftHandlerEvent::{}] error: {}", event_name, e);
+                self.errors.record(&e);
+                e.to_string()
+            }
+        };
+
+        self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: response(response_message.into_bytes(), connection_id),
+        });
     }
 }
 
@@ -242,6 +1319,36 @@ where
 //            },
 //            ConnectedPoint::Listener { .. } => {}
 //        };
+        // Connections from peers which are not in the membership are closed
+        if !self.membership.contains(&peer_id) {
+            eprintln!("[Pbft::inject_connected] the peer is not in the membership, closing the connection. peer_id: {:?}", peer_id);
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id,
+                event: PbftHandlerIn::Disconnect,
+            });
+            return;
+        }
+
+        // The replica sends a fresh session key to the peer when the connection is established
+        if self.authentication == AuthenticationMode::Authenticators {
+            let key = self.session_keys.generate(peer_id.clone());
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id: peer_id.clone(),
+                event: PbftHandlerIn::NewKeyRequest(self.sign(NewKey::new(&peer_id, key))),
+            });
+        }
+        // A replica that is fetching the state asks the peers that connect in the meantime as well
+        if self.state_transfer.is_in_progress() {
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
+                peer_id: peer_id.clone(),
+                event: PbftHandlerIn::FetchStateRequest(self.sign(FetchState::new(self.state.last_executed()))),
+            });
+        }
+        // The messages the peer has missed while it was disconnected are sent again
+        for message in self.retransmissions.resume(&peer_id) {
+            let event = self.tracked_to_handler_in(&message);
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent { peer_id: peer_id.clone(), event });
+        }
         self.connected_peers.insert(peer_id);
         println!("[Pbft::inject_connected] connected_peers: {:?}, addresses: {:?}", self.connected_peers, self.addresses);
     }
@@ -253,101 +1360,562 @@ where
 //            ConnectedPoint::Listener { local_addr: _, send_back_addr } => send_back_addr
 //        };
         self.connected_peers.remove(peer_id);
+        self.session_keys.remove(peer_id);
         println!("[Pbft::inject_disconnected] connected_peers: {:?}, addresses: {:?}", self.connected_peers, self.addresses);
     }
 
     fn inject_node_event(&mut self, peer_id: PeerId, handler_event: PbftHandlerEvent) {
         println!("[Pbft::inject_node_event] handler_event: {:?}", handler_event);
+        if !self.membership.contains(&peer_id) {
+            eprintln!("[Pbft::inject_node_event] the event from a peer not in the membership was dropped. peer_id: {:?}", peer_id);
+            return;
+        }
         match handler_event {
             PbftHandlerEvent::ProcessPrePrepareRequest { request, connection_id } => {
                 println!("[Pbft::inject_node_event] [PbftHandlerEvent::PrePrepareRequest] request: {:?}", request);
-                self.process_pre_prepare(request.clone()).unwrap(); // TODO: error handling
-
-                self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
-                    peer_id,
-                    event: PbftHandlerIn::PrePrepareResponse("OK".into(), connection_id),
-                });
+                let result = self.process_received_pre_prepare(&peer_id, request);
+                self.respond(peer_id, "PrePrepareRequest", result, PbftHandlerIn::PrePrepareResponse, connection_id);
             }
-            PbftHandlerEvent::Response { response } => {
-                let response_message = String::from_utf8(response).expect("Failed to parse response");
-                println!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] response_message: {:?}", response_message);
+            PbftHandlerEvent::MalformedMessage { error } => {
+                eprintln!("[Pbft::inject_node_event] [PbftHandlerEvent::MalformedMessage] error: {}", error);
+                self.errors.record(&PbftError::MalformedMessage(error));
+            }
+            PbftHandlerEvent::Response { id, response } => {
+                let response_message = String::from_utf8_lossy(&response);
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] id: {:?}, response_message: {:?}", id, response_message);
                 if response_message == "OK" {
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] the communications has done successfully")
+                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] the communications has done successfully");
+                    if let Some(id) = id {
+                        self.retransmissions.ack(peer_id, id);
+                    }
                 } else {
-                    // TODO: retry?
-                    eprintln!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] response_message: {:?}", response_message);
+                    // The message is retransmitted if its delivery is tracked, as the peer may accept it
+                    // later, e.g. once it has received the pre-prepare that a prepare matches
+                    eprintln!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] id: {:?}, response_message: {:?}", id, response_message);
                 }
             }
             PbftHandlerEvent::ProcessPrepareRequest { request, connection_id } => {
                 println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessPrepareRequest] request: {:?}", request);
-                self.validate_prepare(&request).unwrap();
-                self.state.insert_prepare(peer_id.clone(), request.clone());
-
-                self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
-                    peer_id,
-                    event: PbftHandlerIn::PrepareResponse("OK".into(), connection_id)
-                });
-
-                if self.prepared(request.view(), request.sequence_number()) {
-                    let commit: Commit = request.into();
-                    for p in self.connected_peers.iter() {
-                        self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
-                            peer_id: p.clone(),
-                            event: PbftHandlerIn::CommitRequest(commit.clone())
-                        })
-                    }
-                }
+                let result = self.process_prepare(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessPrepareRequest", result, PbftHandlerIn::PrepareResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessViewChangeRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessViewChangeRequest] request: {:?}", request);
+                let result = self.process_view_change(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessViewChangeRequest", result, PbftHandlerIn::ViewChangeResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessNewViewRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessNewViewRequest] request: {:?}", request);
+                let result = self.process_new_view(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessNewViewRequest", result, PbftHandlerIn::NewViewResponse, connection_id);
             }
             PbftHandlerEvent::ProcessCommitRequest { request, connection_id } => {
                 println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] request: {:?}", request);
-
-                self.validate_commit(&request).unwrap();
-
-                self.queued_events.push_back(NetworkBehaviourAction::SendEvent {
-                    peer_id: peer_id.clone(),
-                    event: PbftHandlerIn::CommitResponse("OK".into(), connection_id)
-                });
-
-                // Replicas accept commit messages and insert them in their log
-                self.state.insert_commit(peer_id, request.clone());
-
-                // Each replica _i_ executes the operation requested by _m_ after `committed-local(m, v, n, i)` is true
-                if self.committed_local(request.view(), request.sequence_number()) {
-                    let client_request =
-                        self.state.get_pre_prepare_by_key(request.view(), request.sequence_number()).unwrap().client_reqeust();
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] client_message: {:?}", client_request);
-
-                    // Discard requests whose timestamp is lower than the timestamp in the last reply this node sent to the client to guarantee exactly-once semantics.
-                    if client_request.timestamp() <= self.state.last_timestamp() {
-                        eprintln!(
-                            "[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] the request was discarded as its timestamp is lower than the last timestamp. last_timestamp: {:?}",
-                            self.state.last_timestamp()
-                        );
-                        return;
-                    }
-
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] the operation has been executed: {:?}", client_request.operation());
-
-                    // After executing the requested operation, replicas send a reply to the client.
-                    let reply = ClientReply::new(
-                        PeerId::from_public_key(self.keypair.public()),
-                        client_request,
-                        &request
-                    );
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] reply: {:?}", reply);
-                    self.state.update_last_timestamp(reply.timestamp());
-                    self.client_replies.write().unwrap().push_back(reply);
-                }
+                let result = self.process_commit(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessCommitRequest", result, PbftHandlerIn::CommitResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessCheckpointRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCheckpointRequest] request: {:?}", request);
+                let result = self.process_checkpoint(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessCheckpointRequest", result, PbftHandlerIn::CheckpointResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessNewKeyRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessNewKeyRequest] request: {:?}", request);
+                let result = self.process_new_key(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessNewKeyRequest", result, PbftHandlerIn::NewKeyResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessFetchStateRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessFetchStateRequest] request: {:?}", request);
+                let result = self.process_fetch_state(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessFetchStateRequest", result, PbftHandlerIn::FetchStateResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessStateSnapshotRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessStateSnapshotRequest] request: {}", request);
+                let result = self.process_state_snapshot(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessStateSnapshotRequest", result, PbftHandlerIn::StateSnapshotResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessFetchPartitionsRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessFetchPartitionsRequest] request: {}", request);
+                let result = self.process_fetch_partitions(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessFetchPartitionsRequest", result, PbftHandlerIn::FetchPartitionsResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessPartitionsRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessPartitionsRequest] request: {}", request);
+                let result = self.process_partitions(peer_id.clone(), request);
+                self.respond(peer_id, "ProcessPartitionsRequest", result, PbftHandlerIn::PartitionsResponse, connection_id);
+            }
+            PbftHandlerEvent::ProcessForwardRequest { request, connection_id } => {
+                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessForwardRequest] request: {:?}", request);
+                let result = self.process_forwarded_request(request);
+                self.respond(peer_id, "ProcessForwardRequest", result, PbftHandlerIn::ForwardResponse, connection_id);
             }
         }
     }
 
     fn poll(&mut self, _: &mut impl PollParameters) -> Async<NetworkBehaviourAction<PbftHandlerIn, PbftEvent>> {
         println!("[Pbft::poll]");
+        if let Async::Ready(()) = self.request_timers.poll() {
+            // The timer of the backup expires in view _v_, so the backup starts a view change to move the system to view _v_ + 1
+            let new_view = self.state.current_view() + 1;
+            self.start_view_change(new_view);
+        }
+
+        // A replica that has fallen behind fetches the state again until it reaches the checkpoint
+        if let Async::Ready(sequence_number) = self.state_transfer.poll(self.state.last_executed()) {
+            self.start_state_transfer(sequence_number);
+        }
+
+        // The messages up to the stable checkpoint are no longer needed, as a replica that has missed
+        // them catches up with state transfer
+        self.retransmissions.discard_up_to(self.state.stable_checkpoint().sequence_number());
+        for (peer_id, message) in self.retransmissions.poll(&self.connected_peers) {
+            let event = self.tracked_to_handler_in(&message);
+            self.queued_events.push_back(NetworkBehaviourAction::SendEvent { peer_id, event });
+        }
+
+        // The primary starts new instances as long as the pipeline has room for them, so that the
+        // pipeline resumes as the instances in flight are executed
+        while self.can_send_pre_prepare() {
+            match self.batcher.poll() {
+                Async::Ready(client_requests) => self.send_pre_prepare(client_requests),
+                Async::NotReady => break,
+            }
+        }
+
         if let Some(event) = self.queued_events.pop_front() {
             println!("[Pbft::poll] event: {:?}", event);
             return Async::Ready(event);
         }
         Async::NotReady
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::KeyValueStore;
+    use crate::error::ErrorKind;
+    use tokio::net::TcpStream;
+
+    fn keypairs(n: usize) -> Vec<Keypair> {
+        (0..n).map(|_| Keypair::generate_ed25519()).collect()
+    }
+
+    fn peer_id(keypair: &Keypair) -> PeerId {
+        PeerId::from_public_key(keypair.public())
+    }
+
+    // The replica `local` of a cluster made up of the replicas with the keypairs. The replicas start
+    // in view 1, whose primary is replica 1.
+    fn replica(keypairs: &[Keypair], local: usize) -> Pbft<TcpStream> {
+        recovered_replica(keypairs, local, State::new())
+    }
+
+    // The replica `local`, restarted with the protocol state rebuilt from its log
+    fn recovered_replica(keypairs: &[Keypair], local: usize, state: State) -> Pbft<TcpStream> {
+        Pbft::new(
+            keypairs[local].clone(),
+            Arc::new(RwLock::new(VecDeque::new())),
+            Quorum::new(keypairs.len()),
+            WaterMarks::new(200),
+            Box::new(KeyValueStore::new()),
+            AuthenticationMode::Signatures,
+            Membership::new(keypairs.iter().map(peer_id).collect()),
+            Batcher::new(10, Duration::from_millis(50)),
+            Pipeline::new(20),
+            state,
+        )
+    }
+
+    // Stores the pre-prepares for the sequence numbers 1..=n in the log and returns the commits for them
+    fn commits(pbft: &mut Pbft<TcpStream>, n: u64) -> Vec<Commit> {
+        (1..=n).map(|sequence_number| {
+            let pre_prepare = PrePrepare::null(1, sequence_number);
+            let commit = Commit::from(Prepare::from(&pre_prepare));
+            pbft.state.insert_pre_prepare(pbft.sign(pre_prepare));
+            commit
+        }).collect()
+    }
+
+    #[test]
+    fn committed_requests_are_executed_in_order() {
+        let mut pbft = replica(&keypairs(1), 0);
+        let commits = commits(&mut pbft, 3);
+
+        // The request 3 waits for the requests 1 and 2
+        pbft.enqueue_committed_request(commits[2].clone());
+        assert_eq!(pbft.state.last_executed(), 0);
+
+        pbft.enqueue_committed_request(commits[0].clone());
+        assert_eq!(pbft.state.last_executed(), 1);
+
+        pbft.enqueue_committed_request(commits[1].clone());
+        assert_eq!(pbft.state.last_executed(), 3);
+        assert!(pbft.execution_queue.is_empty());
+    }
+
+    #[test]
+    fn executed_request_is_not_queued_again() {
+        let mut pbft = replica(&keypairs(1), 0);
+        let commits = commits(&mut pbft, 1);
+
+        pbft.enqueue_committed_request(commits[0].clone());
+        pbft.enqueue_committed_request(commits[0].clone());
+        assert_eq!(pbft.state.last_executed(), 1);
+        assert!(pbft.execution_queue.is_empty());
+    }
+
+    fn client_request(client: &Keypair, operation: &str, timestamp: u64) -> ClientRequest {
+        ClientRequest::new(operation.to_owned(), timestamp, "127.0.0.1:9000".parse().unwrap(), client)
+    }
+
+    // Orders the request in the next sequence number and executes it
+    fn execute_request(pbft: &mut Pbft<TcpStream>, client_request: ClientRequest) {
+        let pre_prepare = PrePrepare::from(1, pbft.state.last_executed() + 1, vec![client_request]);
+        let commit = Commit::from(Prepare::from(&pre_prepare));
+        pbft.state.insert_pre_prepare(pbft.sign(pre_prepare));
+        pbft.enqueue_committed_request(commit);
+    }
+
+    #[test]
+    fn retransmitted_request_is_answered_with_the_last_reply() {
+        let mut pbft = replica(&keypairs(1), 0);
+        let request = client_request(&Keypair::generate_ed25519(), "PUT key value", 1);
+        execute_request(&mut pbft, request.clone());
+        assert_eq!(pbft.client_replies.read().unwrap().len(), 1);
+
+        pbft.add_client_request(request);
+        // The request is not ordered again
+        assert_eq!(pbft.pre_prepare_sequence.value(), 0);
+        let replies = pbft.client_replies.read().unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1].timestamp(), 1);
+    }
+
+    #[test]
+    fn last_replies_are_kept_per_client() {
+        let mut pbft = replica(&keypairs(1), 0);
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        execute_request(&mut pbft, client_request(&a, "PUT key a", 5));
+
+        // A request from another client is executed even if its timestamp is lower
+        execute_request(&mut pbft, client_request(&b, "PUT key b", 1));
+        assert_eq!(pbft.client_replies.read().unwrap().len(), 2);
+
+        // An older request from the same client is discarded
+        execute_request(&mut pbft, client_request(&a, "PUT key c", 4));
+        assert_eq!(pbft.client_replies.read().unwrap().len(), 2);
+        assert_eq!(pbft.service.execute("GET key"), "b");
+    }
+
+    #[test]
+    fn primary_keeps_the_requests_beyond_the_pipeline_window_queued() {
+        let keypairs = keypairs(4);
+        // The replica 1 is the primary of view 1
+        let mut pbft = replica(&keypairs, 1);
+        pbft.pipeline = Pipeline::new(2);
+        pbft.connected_peers.extend([0, 2, 3].iter().map(|&i| peer_id(&keypairs[i])));
+        let client = Keypair::generate_ed25519();
+
+        assert!(pbft.can_send_pre_prepare());
+        pbft.send_pre_prepare(vec![client_request(&client, "PUT a 1", 1)]);
+        pbft.send_pre_prepare(vec![client_request(&client, "PUT b 2", 2)]);
+        assert!(!pbft.can_send_pre_prepare());
+
+        // The pipeline resumes as the instances in flight are executed
+        let pre_prepare = pbft.state.get_pre_prepare_by_key(1, 1).unwrap().clone();
+        pbft.enqueue_committed_request(Commit::from(Prepare::from(&pre_prepare)));
+        assert!(pbft.can_send_pre_prepare());
+    }
+
+    fn pre_prepare(keypair: &Keypair, view: u64, sequence_number: u64) -> Signed<PrePrepare> {
+        let request = client_request(&Keypair::generate_ed25519(), "PUT key value", 1);
+        Signed::new(PrePrepare::from(view, sequence_number, vec![request]), keypair)
+    }
+
+    #[test]
+    fn pre_prepare_from_the_primary_is_accepted() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+
+        let result = pbft.process_received_pre_prepare(&peer_id(&keypairs[1]), pre_prepare(&keypairs[1], 1, 1));
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(pbft.state.get_pre_prepare_by_key(1, 1).is_some());
+    }
+
+    #[test]
+    fn pre_prepare_from_a_backup_is_dropped() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+
+        let result = pbft.process_received_pre_prepare(&peer_id(&keypairs[3]), pre_prepare(&keypairs[3], 1, 1));
+        assert!(result.is_err());
+        assert!(pbft.state.get_pre_prepare_by_key(1, 1).is_none());
+    }
+
+    #[test]
+    fn prepare_from_the_primary_is_not_counted() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+        let pre_prepare = pre_prepare(&keypairs[1], 1, 1);
+        let prepare = Prepare::from(pre_prepare.message());
+        pbft.process_received_pre_prepare(&peer_id(&keypairs[1]), pre_prepare).unwrap();
+
+        // The local prepare and the one from the primary would make 2f = 2
+        let result = pbft.process_prepare(peer_id(&keypairs[1]), Envelope::Signed(Signed::new(prepare.clone(), &keypairs[1])));
+        assert!(result.is_err());
+        assert!(!pbft.prepared(1, 1));
+
+        pbft.process_prepare(peer_id(&keypairs[3]), Envelope::Signed(Signed::new(prepare, &keypairs[3]))).unwrap();
+        assert!(pbft.prepared(1, 1));
+    }
+
+    // A view change to view 2 carrying a prepared certificate for a pre-prepare signed by `primary`
+    // with prepares signed by `backups`
+    fn view_change(keypairs: &[Keypair], primary: usize, backups: &[usize]) -> ViewChange {
+        let pre_prepare = pre_prepare(&keypairs[primary], 1, 1);
+        let prepare = Prepare::from(pre_prepare.message());
+        let prepares = backups.iter()
+            .map(|&i| Envelope::Signed(Signed::new(prepare.clone(), &keypairs[i])))
+            .collect();
+        ViewChange::new(2, 0, Vec::new(), vec![PreparedCertificate::new(pre_prepare, prepares)])
+    }
+
+    #[test]
+    fn prepared_certificate_with_2f_backup_prepares_is_valid() {
+        let keypairs = keypairs(4);
+        let pbft = replica(&keypairs, 2);
+
+        let result = pbft.validate_view_change_proofs(&view_change(&keypairs, 1, &[2, 3]));
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[test]
+    fn prepared_certificate_does_not_count_the_primary() {
+        let keypairs = keypairs(4);
+        let pbft = replica(&keypairs, 2);
+
+        assert!(pbft.validate_view_change_proofs(&view_change(&keypairs, 1, &[1, 3])).is_err());
+    }
+
+    #[test]
+    fn prepared_certificate_from_a_backup_is_rejected() {
+        let keypairs = keypairs(4);
+        let pbft = replica(&keypairs, 2);
+
+        assert!(pbft.validate_view_change_proofs(&view_change(&keypairs, 3, &[0, 2])).is_err());
+    }
+
+    // A new-view message for view 2, whose primary is replica 2, with the pre-prepares `O`
+    fn new_view(keypairs: &[Keypair], pre_prepares: Vec<Signed<PrePrepare>>) -> Signed<NewView> {
+        let view_changes = [0, 1, 2].iter()
+            .map(|&i| Signed::new(ViewChange::new(2, 0, Vec::new(), Vec::new()), &keypairs[i]))
+            .collect();
+        Signed::new(NewView::new(2, view_changes, pre_prepares), &keypairs[2])
+    }
+
+    #[test]
+    fn new_view_is_entered() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 3);
+
+        let result = pbft.process_new_view(peer_id(&keypairs[2]), new_view(&keypairs, Vec::new()));
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(pbft.state.current_view(), 2);
+        assert!(pbft.state.is_view_active());
+    }
+
+    #[test]
+    fn new_view_with_a_pre_prepare_for_another_view_is_rejected() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 3);
+
+        let result = pbft.process_new_view(peer_id(&keypairs[2]), new_view(&keypairs, vec![pre_prepare(&keypairs[2], 1, 1)]));
+        assert!(result.is_err());
+        assert_eq!(pbft.state.current_view(), 1);
+        assert!(pbft.state.get_pre_prepare_by_key(1, 1).is_none());
+    }
+
+    #[test]
+    fn new_view_with_a_pre_prepare_not_signed_by_the_primary_is_rejected() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 3);
+
+        let result = pbft.process_new_view(peer_id(&keypairs[2]), new_view(&keypairs, vec![pre_prepare(&keypairs[1], 2, 1)]));
+        assert!(result.is_err());
+        assert_eq!(pbft.state.current_view(), 1);
+        assert!(pbft.state.get_pre_prepare_by_key(2, 1).is_none());
+    }
+
+    // The other replicas have produced matching checkpoints for the sequence number
+    fn receive_checkpoints(pbft: &mut Pbft<TcpStream>, keypairs: &[Keypair], sequence_number: u64) {
+        for &i in [0, 1, 3].iter() {
+            let checkpoint = Signed::new(Checkpoint::new(sequence_number, "digest".to_owned()), &keypairs[i]);
+            pbft.process_checkpoint(peer_id(&keypairs[i]), checkpoint).unwrap();
+        }
+    }
+
+    #[test]
+    fn state_transfer_starts_when_requests_are_missing_from_the_log() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+
+        receive_checkpoints(&mut pbft, &keypairs, CHECKPOINT_PERIOD);
+        assert!(pbft.state_transfer.is_in_progress());
+    }
+
+    #[test]
+    fn state_transfer_does_not_start_when_requests_can_be_executed_from_the_log() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+        for n in 1..=CHECKPOINT_PERIOD {
+            pbft.process_received_pre_prepare(&peer_id(&keypairs[1]), pre_prepare(&keypairs[1], 1, n)).unwrap();
+        }
+
+        receive_checkpoints(&mut pbft, &keypairs, CHECKPOINT_PERIOD);
+        assert!(!pbft.state_transfer.is_in_progress());
+        assert_eq!(pbft.state.stable_checkpoint().sequence_number(), 0);
+    }
+
+    #[test]
+    fn prepared_certificate_with_authenticated_prepares_is_rejected() {
+        let keypairs = keypairs(4);
+        let pbft = replica(&keypairs, 2);
+
+        let pre_prepare = pre_prepare(&keypairs[1], 1, 1);
+        let prepare = Prepare::from(pre_prepare.message());
+        let prepares = [2, 3].iter()
+            .map(|&i| Envelope::Authenticated(Authenticated::new(prepare.clone(), &peer_id(&keypairs[i]), &HashMap::new())))
+            .collect();
+        let view_change = ViewChange::new(2, 0, Vec::new(), vec![PreparedCertificate::new(pre_prepare, prepares)]);
+        assert!(pbft.validate_view_change_proofs(&view_change).is_err());
+    }
+
+    #[test]
+    fn batched_requests_are_forwarded_to_the_new_primary() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 1);
+        let client = Keypair::generate_ed25519();
+        pbft.add_client_request(ClientRequest::new("PUT key value".to_owned(), 1, "127.0.0.1:9000".parse().unwrap(), &client));
+
+        pbft.process_new_view(peer_id(&keypairs[2]), new_view(&keypairs, Vec::new())).unwrap();
+        assert!(pbft.batcher.drain().is_empty());
+        let forwarded = pbft.queued_events.iter().any(|event| match event {
+            NetworkBehaviourAction::SendEvent { peer_id: p, event: PbftHandlerIn::ForwardRequest(_) } => p == &peer_id(&keypairs[2]),
+            _ => false,
+        });
+        assert!(forwarded);
+    }
+
+    #[test]
+    fn malformed_messages_are_counted() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+
+        pbft.inject_node_event(peer_id(&keypairs[1]), PbftHandlerEvent::MalformedMessage { error: "garbage".to_owned() });
+        assert_eq!(pbft.error_counts().get(ErrorKind::MalformedMessage), 1);
+
+        // the events from peers that are not in the membership are dropped
+        let outsider = Keypair::generate_ed25519();
+        pbft.inject_node_event(peer_id(&outsider), PbftHandlerEvent::MalformedMessage { error: "garbage".to_owned() });
+        assert_eq!(pbft.error_counts().total(), 1);
+    }
+
+    #[test]
+    fn primary_does_not_send_a_prepare() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 1);
+
+        pbft.process_pre_prepare(pre_prepare(&keypairs[1], 1, 1)).unwrap();
+        assert!(pbft.backup_prepares(1, 1, pbft.state.get_pre_prepare_by_key(1, 1).unwrap().digest()).is_empty());
+    }
+
+    // The peers the queued events send a message to, if the message matches `f`
+    fn recipients(pbft: &Pbft<TcpStream>, f: fn(&PbftHandlerIn) -> bool) -> Vec<PeerId> {
+        pbft.queued_events.iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::SendEvent { peer_id, event } if f(event) => Some(peer_id.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_prepare(event: &PbftHandlerIn) -> bool {
+        matches!(event, PbftHandlerIn::PrepareRequest(_))
+    }
+
+    fn is_commit(event: &PbftHandlerIn) -> bool {
+        matches!(event, PbftHandlerIn::CommitRequest(_))
+    }
+
+    #[test]
+    fn retransmitted_pre_prepare_is_answered_with_the_prepare_to_the_primary_only() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+        pbft.connected_peers.extend([0, 1, 3].iter().map(|&i| peer_id(&keypairs[i])));
+        let pre_prepare = pre_prepare(&keypairs[1], 1, 1);
+
+        pbft.process_received_pre_prepare(&peer_id(&keypairs[1]), pre_prepare.clone()).unwrap();
+        assert_eq!(recipients(&pbft, is_prepare).len(), 3);
+        pbft.queued_events.clear();
+
+        pbft.process_received_pre_prepare(&peer_id(&keypairs[1]), pre_prepare).unwrap();
+        assert_eq!(recipients(&pbft, is_prepare), vec![peer_id(&keypairs[1])]);
+    }
+
+    #[test]
+    fn commit_is_sent_once() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+        pbft.connected_peers.extend([0, 1, 3].iter().map(|&i| peer_id(&keypairs[i])));
+        let pre_prepare = pre_prepare(&keypairs[1], 1, 1);
+        let prepare = Prepare::from(pre_prepare.message());
+        pbft.process_received_pre_prepare(&peer_id(&keypairs[1]), pre_prepare).unwrap();
+
+        // The prepare from replica 0 completes the certificate with the replica's own prepare
+        pbft.process_prepare(peer_id(&keypairs[0]), Envelope::Signed(Signed::new(prepare.clone(), &keypairs[0]))).unwrap();
+        assert_eq!(recipients(&pbft, is_commit).len(), 3);
+
+        // Neither a duplicate nor a later prepare sends the commit again
+        pbft.process_prepare(peer_id(&keypairs[0]), Envelope::Signed(Signed::new(prepare.clone(), &keypairs[0]))).unwrap();
+        pbft.process_prepare(peer_id(&keypairs[3]), Envelope::Signed(Signed::new(prepare, &keypairs[3]))).unwrap();
+        assert_eq!(recipients(&pbft, is_commit).len(), 3);
+    }
+
+    #[test]
+    fn recovered_replica_that_is_changing_views_starts_the_view_change_timer() {
+        let keypairs = keypairs(4);
+        let mut state = State::new();
+        state.start_view_change(2);
+
+        let pbft = recovered_replica(&keypairs, 0, state);
+        assert!(pbft.request_timers.is_changing_view());
+        assert!(!replica(&keypairs, 0).request_timers.is_changing_view());
+    }
+
+    #[test]
+    fn view_change_too_far_ahead_is_rejected() {
+        let keypairs = keypairs(4);
+        let pbft = replica(&keypairs, 0);
+
+        let result = pbft.validate_view_change(&ViewChange::new(1 + MAX_VIEW_CHANGE_DISTANCE, 0, Vec::new(), Vec::new()));
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(pbft.validate_view_change(&ViewChange::new(2 + MAX_VIEW_CHANGE_DISTANCE, 0, Vec::new(), Vec::new())).is_err());
+    }
+
+    #[test]
+    fn prepare_is_accepted_only_in_the_current_active_view() {
+        let keypairs = keypairs(4);
+        let mut pbft = replica(&keypairs, 2);
+        let pre_prepare = pre_prepare(&keypairs[1], 1, 1);
+        let prepare = Prepare::from(pre_prepare.message());
+        pbft.process_received_pre_prepare(&peer_id(&keypairs[1]), pre_prepare).unwrap();
+        assert!(pbft.validate_prepare(&prepare).is_ok());
+
+        pbft.state.start_view_change(2);
+        assert!(pbft.validate_prepare(&prepare).is_err());
+
+        pbft.state.install_view(2);
+        assert!(pbft.validate_prepare(&prepare).is_err());
+    }
+}
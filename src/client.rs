@@ -0,0 +1,22 @@
+use std::net::{SocketAddr, TcpStream};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::message::{ClientRequest, Message};
+use crate::key_file;
+
+// The keypair of the client is stored in this file so that the client keeps its identity across runs
+const CLIENT_KEY_FILE: &str = "client.key";
+
+// Sends a signed request to a replica. The replies are sent to `reply_address`.
+pub fn run(replica: SocketAddr, reply_address: SocketAddr, operation: String) {
+    let keypair = key_file::load_or_generate(CLIENT_KEY_FILE).unwrap();
+
+    // The client uses its local clock as the timestamp so that the timestamps are totally ordered
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("The system clock is before the epoch").as_millis() as u64;
+    let client_request = ClientRequest::new(operation, timestamp, reply_address, &keypair);
+    println!("[client::run] client_id: {}, client_request: {:?}", client_request.client_id(), client_request);
+
+    let mut stream = TcpStream::connect(replica).expect("Failed to connect to the replica");
+    stream.write_all(Message::ClientRequest(client_request).to_string().as_bytes()).expect("Failed to send the request");
+    println!("[client::run] the request has been sent to {:?}", replica);
+}
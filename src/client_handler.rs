@@ -3,10 +3,9 @@ use std::sync::{Arc, RwLock};
 use std::io::{Read, Write};
 use crate::message::{ClientRequest, Message, ClientReply};
 use std::collections::VecDeque;
-use crate::node_type::NodeType;
+use std::convert::TryFrom;
 
 pub struct ClientHandler {
-    node_type: NodeType,
     listener: TcpListener,
     client_requests: Arc<RwLock<VecDeque<ClientRequest>>>,
     client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
@@ -15,22 +14,16 @@ pub struct ClientHandler {
 
 impl ClientHandler {
     pub fn new(
-        node_type: NodeType,
+        client_port: u16,
         client_requests: Arc<RwLock<VecDeque<ClientRequest>>>,
         client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
     ) -> Self {
-        let address = if node_type == NodeType::Primary {
-            // Make the port number of primary node fixed in order to easily debug
-            "127.0.0.1:8000"
-        } else {
-            "127.0.0.1:0"
-        };
-        let listener = TcpListener::bind(address).unwrap();
+        // The clients may run on other hosts than the replica, so it listens on all the interfaces
+        let listener = TcpListener::bind(("0.0.0.0", client_port)).unwrap();
         listener.set_nonblocking(true).expect("Cannot set non-blocking");
         println!("[ClientHandler::new] Listening on {:?}", listener.local_addr().unwrap());
 
         Self {
-            node_type,
             listener,
             client_requests,
             client_replies,
@@ -42,8 +35,10 @@ impl ClientHandler {
         println!("[ClientHandler::tick]");
 
         // Accept an incoming stream
-        if let Some(tcp_stream) = self.incoming().unwrap() {
-            self.stream_states.push_back(ClientStreamState::WaitingForIncomingStream(tcp_stream));
+        match self.incoming() {
+            Ok(Some(tcp_stream)) => self.stream_states.push_back(ClientStreamState::WaitingForIncomingStream(tcp_stream)),
+            Ok(None) => {}
+            Err(e) => eprintln!("[ClientHandler::tick] Failed to accept the incoming stream. error: {:?}", e),
         }
 
         // Consume a job to reply to client
@@ -55,27 +50,32 @@ impl ClientHandler {
             match state {
                 ClientStreamState::WaitingForIncomingStream(tcp_stream) => {
                     println!("[ClientHandler::tick] [ClientStreamState::WaitingForIncomingStream]");
-                    let new_state = self.read_client_stream(tcp_stream).unwrap();
-                    self.stream_states.push_back(new_state);
+                    match self.read_client_stream(tcp_stream) {
+                        Ok(Some(new_state)) => self.stream_states.push_back(new_state),
+                        Ok(None) => {}
+                        Err(e) => eprintln!("[ClientHandler::tick] [ClientStreamState::WaitingForIncomingStream] Failed to read the client stream. error: {:?}", e),
+                    }
                 }
                 ClientStreamState::ReceivedClientMessage(message) => {
                     println!("[ClientHandler::tick] [ClientStreamState::ReceivedClientMessage] message: {:?}", message);
                     match message {
+                        // Backups relay the request to the primary
                         Message::ClientRequest(client_request) => {
-                            if self.node_type == NodeType::Backup {
-                                // TODO: transfer the message to primary replica if this node is running as backup
-                                eprintln!("Can't process the client request as running as backup. client_request: {:?}", client_request);
-                                return;
-                            }
                             self.client_requests.write().unwrap().push_back(client_request);
                         }
-                        _ => unreachable!()
+                        _ => eprintln!("[ClientHandler::tick] [ClientStreamState::ReceivedClientMessage] the message was dropped as it is not a client request. message: {:?}", message),
                     }
                 }
                 ClientStreamState::PrepareToSendReply(reply) => {
                     println!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] reply: {:?}", reply);
-                    let mut stream = TcpStream::connect(reply.client_address()).unwrap();
-                    stream.set_nonblocking(true).expect("Cannot set non-blocking");
+                    // The address is given by the client, so it may be unreachable
+                    let mut stream = match TcpStream::connect(reply.client_address()).and_then(|stream| stream.set_nonblocking(true).map(|()| stream)) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] Failed to connect to the client. error: {:?}, reply: {:?}", e, reply);
+                            return;
+                        }
+                    };
 
                     match stream.write(reply.to_string().as_bytes()) {
                         Ok(_size) => println!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] Sent the reply to the client. reply: {:?}", reply),
@@ -100,17 +100,25 @@ impl ClientHandler {
         unreachable!();
     }
 
-    fn read_client_stream(&self, mut tcp_stream: TcpStream) -> Result<ClientStreamState, std::io::Error> {
-        let mut buffer = [0u8; 512];
+    // Returns None if the message is malformed, in which case the stream is dropped
+    fn read_client_stream(&self, mut tcp_stream: TcpStream) -> Result<Option<ClientStreamState>, std::io::Error> {
+        let mut buffer = [0u8; 4096];
         match tcp_stream.read(&mut buffer) {
             Ok(size) => {
-                let message = String::from_utf8_lossy(&buffer[..size]).to_string().into();
-                println!("[ClientHandler::read_client_stream] message: {:?}", message);
-                return Ok(ClientStreamState::ReceivedClientMessage(message));
+                match Message::try_from(&buffer[..size]) {
+                    Ok(message) => {
+                        println!("[ClientHandler::read_client_stream] message: {:?}", message);
+                        return Ok(Some(ClientStreamState::ReceivedClientMessage(message)));
+                    }
+                    Err(e) => {
+                        eprintln!("[ClientHandler::read_client_stream] the malformed message was dropped. error: {}", e);
+                        return Ok(None);
+                    }
+                }
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                 println!("[ClientHandler::read_client_stream] [ErrorKind::WouldBlock] e: {:?}", e);
-                return Ok(ClientStreamState::WaitingForIncomingStream(tcp_stream));
+                return Ok(Some(ClientStreamState::WaitingForIncomingStream(tcp_stream)));
             },
             Err(e) => {
                 println!("encountered IO error: {}", e);
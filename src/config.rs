@@ -0,0 +1,85 @@
+use serde::Deserialize;
+use libp2p::PeerId;
+use libp2p::identity::PublicKey;
+use libp2p::multiaddr::Multiaddr;
+
+// The static membership of the cluster
+#[derive(Debug, Deserialize)]
+pub struct NetworkConfig {
+    replicas: Vec<ReplicaConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplicaConfig {
+    replica_id: usize,
+    // the libp2p address the replica listens on
+    address: String,
+    // the port the replica receives client requests on
+    client_port: u16,
+    // the base58-encoded public key of the replica in the protobuf encoding
+    public_key: String,
+}
+
+impl NetworkConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}. error: {}", path, e))?;
+        let mut config: Self = serde_json::from_str(&json).map_err(|e| format!("Failed to parse {}. error: {}", path, e))?;
+        config.replicas.sort_by_key(|r| r.replica_id);
+        config.validate()?;
+        println!("[NetworkConfig::load] loaded the membership from {}. replicas: {:?}", path, config.replicas);
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.replicas.is_empty() {
+            return Err("No replicas are configured".to_owned());
+        }
+
+        // The replica ids are 0, 1, ..., n - 1 so that the primary of view _v_ is _v mod n_
+        for (i, replica) in self.replicas.iter().enumerate() {
+            if replica.replica_id != i {
+                return Err(format!("The replica ids must be 0..{}. replica_id: {}", self.replicas.len(), replica.replica_id));
+            }
+            replica.address()?;
+            replica.public_key()?;
+        }
+        Ok(())
+    }
+
+    pub fn replicas(&self) -> &Vec<ReplicaConfig> {
+        &self.replicas
+    }
+
+    pub fn replica(&self, replica_id: usize) -> Option<&ReplicaConfig> {
+        self.replicas.get(replica_id)
+    }
+}
+
+impl ReplicaConfig {
+    pub fn replica_id(&self) -> usize {
+        self.replica_id
+    }
+
+    pub fn address(&self) -> Result<Multiaddr, String> {
+        self.address.parse().map_err(|e| format!("Invalid address. replica_id: {}, error: {:?}", self.replica_id, e))
+    }
+
+    pub fn client_port(&self) -> u16 {
+        self.client_port
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey, String> {
+        let bytes = bs58::decode(&self.public_key).into_vec()
+            .map_err(|e| format!("Invalid public key. replica_id: {}, error: {:?}", self.replica_id, e))?;
+        PublicKey::from_protobuf_encoding(&bytes)
+            .map_err(|e| format!("Invalid public key. replica_id: {}, error: {:?}", self.replica_id, e))
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(self.public_key().expect("The public key has been validated"))
+    }
+}
+
+pub fn encode_public_key(public_key: PublicKey) -> String {
+    bs58::encode(public_key.into_protobuf_encoding()).into_string()
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use crate::water_mark::OutOfWaterMarks;
+
+// The reasons a message from a peer is rejected. A faulty or outdated peer can send anything, so the
+// replica reports the error back to the sender as a non-OK response, or drops the message if it can't
+// be decoded, instead of crashing.
+#[derive(Debug)]
+pub enum PbftError {
+    // The bytes can't be decoded into a message
+    MalformedMessage(String),
+    // The signature or the MAC is invalid, or the message is not signed by the sender
+    InvalidAuthentication(String),
+    // The sequence number is not between the water marks
+    OutOfWaterMarks(OutOfWaterMarks),
+    // The message is authentic but can't be accepted in the current state, e.g. it is for another view
+    InvalidMessage(String),
+}
+
+impl PbftError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            PbftError::MalformedMessage(_) => ErrorKind::MalformedMessage,
+            PbftError::InvalidAuthentication(_) => ErrorKind::InvalidAuthentication,
+            PbftError::OutOfWaterMarks(_) => ErrorKind::OutOfWaterMarks,
+            PbftError::InvalidMessage(_) => ErrorKind::InvalidMessage,
+        }
+    }
+}
+
+impl std::error::Error for PbftError {
+}
+
+impl std::fmt::Display for PbftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PbftError::MalformedMessage(e) => write!(f, "Malformed message: {}", e),
+            PbftError::InvalidAuthentication(e) => write!(f, "Invalid authentication: {}", e),
+            PbftError::OutOfWaterMarks(e) => write!(f, "{}", e),
+            PbftError::InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+        }
+    }
+}
+
+impl From<OutOfWaterMarks> for PbftError {
+    fn from(e: OutOfWaterMarks) -> Self {
+        PbftError::OutOfWaterMarks(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    MalformedMessage,
+    InvalidAuthentication,
+    OutOfWaterMarks,
+    InvalidMessage,
+}
+
+// The number of messages from peers rejected for each kind of error, so that a faulty or
+// misconfigured peer can be spotted without going through the logs
+#[derive(Debug, Default)]
+pub struct ErrorCounts {
+    counts: HashMap<ErrorKind, u64>,
+}
+
+impl ErrorCounts {
+    pub fn record(&mut self, error: &PbftError) {
+        *self.counts.entry(error.kind()).or_default() += 1;
+    }
+
+    pub fn get(&self, kind: ErrorKind) -> u64 {
+        self.counts.get(&kind).cloned().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl std::fmt::Display for ErrorCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "total: {}, malformed_message: {}, invalid_authentication: {}, out_of_water_marks: {}, invalid_message: {}",
+            self.total(),
+            self.get(ErrorKind::MalformedMessage),
+            self.get(ErrorKind::InvalidAuthentication),
+            self.get(ErrorKind::OutOfWaterMarks),
+            self.get(ErrorKind::InvalidMessage),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_counts() {
+        let mut counts = ErrorCounts::default();
+        counts.record(&PbftError::InvalidMessage("stale view".to_owned()));
+        counts.record(&PbftError::InvalidMessage("unknown pre-prepare".to_owned()));
+        counts.record(&PbftError::MalformedMessage("garbage".to_owned()));
+
+        assert_eq!(counts.get(ErrorKind::InvalidMessage), 2);
+        assert_eq!(counts.get(ErrorKind::MalformedMessage), 1);
+        assert_eq!(counts.get(ErrorKind::InvalidAuthentication), 0);
+        assert_eq!(counts.total(), 3);
+    }
+}
@@ -1,7 +1,7 @@
 use libp2p::core::Negotiated;
 use libp2p::swarm::protocols_handler::{KeepAlive, ProtocolsHandlerUpgrErr, ProtocolsHandlerEvent, SubstreamProtocol};
 use libp2p::swarm::ProtocolsHandler;
-use crate::message::{Message, PrePrepare, Prepare, Commit};
+use crate::message::{Message, MessageId, Signed, Envelope, ClientRequest, PrePrepare, Prepare, Commit, ViewChange, NewView, Checkpoint, NewKey, FetchState, StateSnapshot, FetchPartitions, Partitions};
 use tokio::prelude::{AsyncRead, AsyncWrite, Async, AsyncSink};
 use crate::behavior::PbftFailure;
 use futures::Poll;
@@ -14,12 +14,32 @@ use std::collections::VecDeque;
 /// Event to send to the handler.
 #[derive(Debug)]
 pub enum PbftHandlerIn {
-    PrePrepareRequest(PrePrepare),
+    PrePrepareRequest(Signed<PrePrepare>),
     PrePrepareResponse(Vec<u8>, ConnectionId),
-    PrepareRequest(Prepare),
+    PrepareRequest(Envelope<Prepare>),
     PrepareResponse(Vec<u8>, ConnectionId),
-    CommitRequest(Commit),
+    CommitRequest(Envelope<Commit>),
     CommitResponse(Vec<u8>, ConnectionId),
+    ViewChangeRequest(Signed<ViewChange>),
+    ViewChangeResponse(Vec<u8>, ConnectionId),
+    NewViewRequest(Signed<NewView>),
+    NewViewResponse(Vec<u8>, ConnectionId),
+    CheckpointRequest(Signed<Checkpoint>),
+    CheckpointResponse(Vec<u8>, ConnectionId),
+    NewKeyRequest(Signed<NewKey>),
+    NewKeyResponse(Vec<u8>, ConnectionId),
+    ForwardRequest(ClientRequest),
+    ForwardResponse(Vec<u8>, ConnectionId),
+    FetchStateRequest(Signed<FetchState>),
+    FetchStateResponse(Vec<u8>, ConnectionId),
+    StateSnapshotRequest(Signed<StateSnapshot>),
+    StateSnapshotResponse(Vec<u8>, ConnectionId),
+    FetchPartitionsRequest(Signed<FetchPartitions>),
+    FetchPartitionsResponse(Vec<u8>, ConnectionId),
+    PartitionsRequest(Signed<Partitions>),
+    PartitionsResponse(Vec<u8>, ConnectionId),
+    // Closes the connection, e.g. as the peer is not in the membership
+    Disconnect,
 }
 
 pub struct PbftHandler<TSubstream>
@@ -29,6 +49,8 @@ where
     config: PbftProtocolConfig,
     substreams: VecDeque<SubstreamState<Negotiated<TSubstream>>>,
     next_connection_id: ConnectionId,
+    // the connection is closed on the next poll
+    disconnecting: bool,
     _marker: std::marker::PhantomData<TSubstream>,
 }
 
@@ -59,9 +81,11 @@ where
     /// Waiting to send a message to the remote.
     OutPendingSend(PbftOutStreamSink<TSubstream>, Message),
     /// Waiting to flush the substream so that the data arrives to the remote.
-    OutPendingFlush(PbftOutStreamSink<TSubstream>),
-    // TODO: add timeout
-    OutWaitingAnswer(PbftOutStreamSink<TSubstream>),
+    /// Contains the id of the message if its delivery is tracked.
+    OutPendingFlush(PbftOutStreamSink<TSubstream>, Option<MessageId>),
+    /// Waiting for the answer from the remote. A message that is never answered is retransmitted
+    /// by the behaviour if its delivery is tracked.
+    OutWaitingAnswer(PbftOutStreamSink<TSubstream>, Option<MessageId>),
     /// The substream is being closed.
     OutClosing(PbftOutStreamSink<TSubstream>),
     /// Waiting for a request from the remote.
@@ -79,18 +103,60 @@ where
 #[derive(Debug)]
 pub enum PbftHandlerEvent {
     ProcessPrePrepareRequest {
-        request: PrePrepare,
+        request: Signed<PrePrepare>,
         connection_id: ConnectionId,
     },
     Response {
+        // the id of the message the response is for, if its delivery is tracked
+        id: Option<MessageId>,
         response: Vec<u8>,
     },
+    // A message from the remote couldn't be decoded, so the substream has been closed
+    MalformedMessage {
+        error: String,
+    },
     ProcessPrepareRequest {
-        request: Prepare,
+        request: Envelope<Prepare>,
         connection_id: ConnectionId,
     },
     ProcessCommitRequest {
-        request: Commit,
+        request: Envelope<Commit>,
+        connection_id: ConnectionId,
+    },
+    ProcessViewChangeRequest {
+        request: Signed<ViewChange>,
+        connection_id: ConnectionId,
+    },
+    ProcessNewViewRequest {
+        request: Signed<NewView>,
+        connection_id: ConnectionId,
+    },
+    ProcessCheckpointRequest {
+        request: Signed<Checkpoint>,
+        connection_id: ConnectionId,
+    },
+    ProcessNewKeyRequest {
+        request: Signed<NewKey>,
+        connection_id: ConnectionId,
+    },
+    ProcessForwardRequest {
+        request: ClientRequest,
+        connection_id: ConnectionId,
+    },
+    ProcessFetchStateRequest {
+        request: Signed<FetchState>,
+        connection_id: ConnectionId,
+    },
+    ProcessStateSnapshotRequest {
+        request: Signed<StateSnapshot>,
+        connection_id: ConnectionId,
+    },
+    ProcessFetchPartitionsRequest {
+        request: Signed<FetchPartitions>,
+        connection_id: ConnectionId,
+    },
+    ProcessPartitionsRequest {
+        request: Signed<Partitions>,
         connection_id: ConnectionId,
     },
 }
@@ -104,6 +170,7 @@ where
             config: PbftProtocolConfig {},
             substreams: VecDeque::with_capacity(100), // FIXME
             next_connection_id: ConnectionId::new(),
+            disconnecting: false,
             _marker: std::marker::PhantomData,
         }
     }
@@ -118,6 +185,20 @@ where
             }
         })
     }
+
+    // Sends the response on the inbound substream that the request has been received on, unless the
+    // substream has been closed in the meantime
+    fn send_response(&mut self, response: Vec<u8>, connection_id: ConnectionId) {
+        if let Some(pos) = self.find_waiting_substream_state_pos(&connection_id) {
+            let substream = match self.substreams.remove(pos) {
+                Some(SubstreamState::InWaitingToProcessMessage(_connection_id, substream)) => substream,
+                _ => unreachable!(),
+            };
+            self.substreams.push_back(SubstreamState::InPendingSend(substream, response));
+        } else {
+            eprintln!("[PbftHandler::send_response] the response was dropped as the substream has been closed, connection_id: {:?}", connection_id);
+        }
+    }
 }
 
 impl<TSubstream> ProtocolsHandler for PbftHandler<TSubstream>
@@ -164,22 +245,7 @@ where
             }
             PbftHandlerIn::PrePrepareResponse(response, connection_id) => {
                 println!("[PbftHandler::inject_event] [PbftHandlerIn::PrePrepareResponse] response: {:?}, connection_id: {:?}", response, connection_id);
-                let pos = self.substreams.iter().position(|state| {
-                    match state {
-                        SubstreamState::InWaitingToProcessMessage(substream_connection_id, _) => substream_connection_id.clone() == connection_id,
-                        _ => false,
-                    }
-                });
-
-                if let Some(pos) = pos {
-                    let (_connection_id, substream) = match self.substreams.remove(pos) {
-                        Some(SubstreamState::InWaitingToProcessMessage(connection_id, substream)) => (connection_id, substream),
-                        _ => unreachable!(),
-                    };
-                    self.substreams.push_back(SubstreamState::InPendingSend(substream, response));
-                } else {
-                    panic!("[PbftHandler::inject_event] [PbftHandlerIn::PrePrepareResponse] substream state is not found, pos: {:?}, connection_id: {:?}", pos, connection_id);
-                }
+                self.send_response(response, connection_id);
             }
             PbftHandlerIn::PrepareRequest(request) => {
                 println!("[PbftHandler::inject_event] [PbftHandlerIn::PrepareRequest] request: {:?}", request);
@@ -189,16 +255,7 @@ where
             }
             PbftHandlerIn::PrepareResponse(response, connection_id) => {
                 println!("[PbftHandler::inject_event] [PbftHandlerIn::PrepareResponse] response: {:?}, connection_id: {:?}", response, connection_id);
-
-                if let Some(pos) = self.find_waiting_substream_state_pos(&connection_id) {
-                    let (_connection_id, substream) = match self.substreams.remove(pos) {
-                        Some(SubstreamState::InWaitingToProcessMessage(connection_id, substream)) => (connection_id, substream),
-                        _ => unreachable!(),
-                    };
-                    self.substreams.push_back(SubstreamState::InPendingSend(substream, response));
-                } else {
-                    panic!("[PbftHandler::inject_event] [PbftHandlerIn::PrepareResponse] substream state is not found, connection_id: {:?}", connection_id);
-                }
+                self.send_response(response, connection_id);
             }
             PbftHandlerIn::CommitRequest(request) => {
                 println!("[PbftHandler::inject_event] [PbftHandlerIn::CommitRequest] request: {:?}", request);
@@ -208,32 +265,126 @@ where
             }
             PbftHandlerIn::CommitResponse(response, connection_id) => {
                 println!("[PbftHandler::inject_event] [PbftHandlerIn::CommitResponse] response: {:?}, connection_id: {:?}", response, connection_id);
-
-                if let Some(pos) = self.find_waiting_substream_state_pos(&connection_id) {
-                    let (_connection_id, substream) = match self.substreams.remove(pos) {
-                        Some(SubstreamState::InWaitingToProcessMessage(connection_id, substream)) => (connection_id, substream),
-                        _ => unreachable!(),
-                    };
-                    self.substreams.push_back(SubstreamState::InPendingSend(substream, response));
-                } else {
-                    panic!("[PbftHandler::inject_event] [PbftHandlerIn::CommitResponse] substream state is not found, connection_id: {:?}", connection_id);
-                }
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::ViewChangeRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::ViewChangeRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::ViewChange(request))
+                )
+            }
+            PbftHandlerIn::ViewChangeResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::ViewChangeResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::NewViewRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::NewViewRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::NewView(request))
+                )
+            }
+            PbftHandlerIn::NewViewResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::NewViewResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::CheckpointRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::CheckpointRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::Checkpoint(request))
+                )
+            }
+            PbftHandlerIn::CheckpointResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::CheckpointResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::NewKeyRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::NewKeyRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::NewKey(request))
+                )
+            }
+            PbftHandlerIn::NewKeyResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::NewKeyResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::ForwardRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::ForwardRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::ClientRequest(request))
+                )
+            }
+            PbftHandlerIn::ForwardResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::ForwardResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::FetchStateRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::FetchStateRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::FetchState(request))
+                )
+            }
+            PbftHandlerIn::FetchStateResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::FetchStateResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::StateSnapshotRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::StateSnapshotRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::StateSnapshot(request))
+                )
+            }
+            PbftHandlerIn::StateSnapshotResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::StateSnapshotResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::FetchPartitionsRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::FetchPartitionsRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::FetchPartitions(request))
+                )
+            }
+            PbftHandlerIn::FetchPartitionsResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::FetchPartitionsResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::PartitionsRequest(request) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::PartitionsRequest] request: {:?}", request);
+                self.substreams.push_back(
+                    SubstreamState::OutPendingOpen(Message::Partitions(request))
+                )
+            }
+            PbftHandlerIn::PartitionsResponse(response, connection_id) => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::PartitionsResponse] response: {:?}, connection_id: {:?}", response, connection_id);
+                self.send_response(response, connection_id);
+            }
+            PbftHandlerIn::Disconnect => {
+                println!("[PbftHandler::inject_event] [PbftHandlerIn::Disconnect]");
+                self.disconnecting = true;
             }
         }
     }
 
     fn inject_dial_upgrade_error(&mut self, info: Message, error: ProtocolsHandlerUpgrErr<std::io::Error>) {
-        println!("PbftHandler::inject_dial_upgrade_error(), info: {:?}, error: {:?}", info, error);
+        eprintln!("PbftHandler::inject_dial_upgrade_error(), the message is retransmitted if its delivery is tracked. info: {:?}, error: {:?}", info, error);
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
 //        println!("PbftHandler::connection_keep_alive()");
+        if self.disconnecting {
+            return KeepAlive::No;
+        }
         KeepAlive::Yes
     }
 
     fn poll(&mut self) -> Poll<ProtocolsHandlerEvent<PbftProtocolConfig, Message, Self::OutEvent>, Self::Error> {
         println!("[PbftHandler::poll]");
 
+        // An error closes the connection
+        if self.disconnecting {
+            println!("[PbftHandler::poll] closing the connection");
+            return Err(PbftFailure);
+        }
+
         for _ in 0..self.substreams.len() {
             if let Some(mut substream_state) = self.substreams.pop_front() {
                 println!("[PbftHandler::poll] [substream_state]");
@@ -260,7 +411,7 @@ where
                             return Ok(Async::Ready(protocol_handler_event));
                         }
                         (None, None, _) => {
-                            // TODO
+                            // The substream has been closed
                             println!("[PbftHandler::poll] (None, None, _)");
                             break;
                         }
@@ -302,11 +453,12 @@ where
         }
         SubstreamState::OutPendingSend(mut substream, message) => {
             println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] message: {:?}", message);
+            let id = message.id();
             match substream.start_send(message) {
                 Ok(AsyncSink::Ready) => {
                     println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] [start_send::Ready]");
                     (
-                        Some(SubstreamState::OutPendingFlush(substream)),
+                        Some(SubstreamState::OutPendingFlush(substream, id)),
                         None,
                         true,
                     )
@@ -320,17 +472,17 @@ where
                     )
                 },
                 Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] [start_send::Err] Err: {:?}", e);
-                    (None, None, false) // TODO
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] [start_send::Err] the message is retransmitted if its delivery is tracked. id: {:?}, Err: {:?}", id, e);
+                    (None, None, false)
                 }
             }
         }
-        SubstreamState::OutPendingFlush(mut substream) => {
+        SubstreamState::OutPendingFlush(mut substream, id) => {
             match substream.poll_complete() {
                 Ok(Async::Ready(())) => {
                     println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingFlush] [Ready]");
                     (
-                        Some(SubstreamState::OutWaitingAnswer(substream)),
+                        Some(SubstreamState::OutWaitingAnswer(substream, id)),
                         None,
                         true,
                     )
@@ -338,43 +490,43 @@ where
                 Ok(Async::NotReady) => {
                     println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingFlush] [NotReady]");
                     (
-                        Some(SubstreamState::OutPendingFlush(substream)),
+                        Some(SubstreamState::OutPendingFlush(substream, id)),
                         None,
                         false,
                     )
                 }
                 Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingFlush] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingFlush] [Err] the message is retransmitted if its delivery is tracked. id: {:?}, Err: {:?}", id, e);
+                    (None, None, false)
                 }
             }
         }
-        SubstreamState::OutWaitingAnswer(mut substream) => {
+        SubstreamState::OutWaitingAnswer(mut substream, id) => {
             println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer]");
             match substream.poll() {
                 Ok(Async::Ready(Some(response))) => {
                     println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Ready::Some] response: {:?}", response);
                     (
                         Some(SubstreamState::OutClosing(substream)),
-                        Some(ProtocolsHandlerEvent::Custom(PbftHandlerEvent::Response { response })),
+                        Some(ProtocolsHandlerEvent::Custom(PbftHandlerEvent::Response { id, response })),
                         true,
                     )
                 }
                 Ok(Async::NotReady) => {
                     println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [NotReady]");
                     (
-                        Some(SubstreamState::OutWaitingAnswer(substream)),
+                        Some(SubstreamState::OutWaitingAnswer(substream, id)),
                         None,
                         false,
                     )
                 }
                 Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Err] the message is retransmitted if its delivery is tracked. id: {:?}, Err: {:?}", id, e);
+                    (None, None, false)
                 }
                 Ok(Async::Ready(None)) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Ready::None]");
-                    (None, None, false) // TODO
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Ready::None] the substream has been closed without an answer. The message is retransmitted if its delivery is tracked. id: {:?}", id);
+                    (None, None, false)
                 }
             }
         }
@@ -393,8 +545,9 @@ where
                     )
                 }
                 Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutClosing] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
+                    // The answer has already been received, so there is nothing to recover
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::OutClosing] [Err] Err: {:?}", e);
+                    (None, None, false)
                 }
             }
         }
@@ -421,8 +574,13 @@ where
                     (None, None, false)
                 },
                 Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InWaitingMessage] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
+                    // The remote retransmits the message as it doesn't receive an answer
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::InWaitingMessage] [Err] Err: {:?}", e);
+                    if e.kind() == std::io::ErrorKind::InvalidData {
+                        let event = PbftHandlerEvent::MalformedMessage { error: e.to_string() };
+                        return (None, Some(ProtocolsHandlerEvent::Custom(event)), false);
+                    }
+                    (None, None, false)
                 }
             }
         }
@@ -453,8 +611,9 @@ where
                     )
                 },
                 Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InPendingSend] [Err]: {:?}", e);
-                    (None, None, false) // TODO
+                    // The remote retransmits the message as it doesn't receive the answer
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::InPendingSend] [Err]: {:?}", e);
+                    (None, None, false)
                 }
             }
         }
@@ -493,8 +652,9 @@ where
                     (Some(SubstreamState::InClosing(substream)), None, false)
                 },
                 Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InClosing] [Err]: {:?}", e);
-                    (None, None, false) // TODO
+                    // The answer has already been sent, so there is nothing to recover
+                    eprintln!("[PbftHandler::handle_substream()] [SubstreamState::InClosing] [Err]: {:?}", e);
+                    (None, None, false)
                 }
             }
         }
@@ -515,6 +675,32 @@ fn message_to_handler_event(
         Message::Commit(commit) => {
             PbftHandlerEvent::ProcessCommitRequest { request: commit, connection_id }
         }
-        Message::ClientRequest(_) => unreachable!()
+        Message::ViewChange(view_change) => {
+            PbftHandlerEvent::ProcessViewChangeRequest { request: view_change, connection_id }
+        }
+        Message::NewView(new_view) => {
+            PbftHandlerEvent::ProcessNewViewRequest { request: new_view, connection_id }
+        }
+        Message::Checkpoint(checkpoint) => {
+            PbftHandlerEvent::ProcessCheckpointRequest { request: checkpoint, connection_id }
+        }
+        Message::NewKey(new_key) => {
+            PbftHandlerEvent::ProcessNewKeyRequest { request: new_key, connection_id }
+        }
+        Message::ClientRequest(client_request) => {
+            PbftHandlerEvent::ProcessForwardRequest { request: client_request, connection_id }
+        }
+        Message::FetchState(fetch_state) => {
+            PbftHandlerEvent::ProcessFetchStateRequest { request: fetch_state, connection_id }
+        }
+        Message::StateSnapshot(state_snapshot) => {
+            PbftHandlerEvent::ProcessStateSnapshotRequest { request: state_snapshot, connection_id }
+        }
+        Message::FetchPartitions(fetch_partitions) => {
+            PbftHandlerEvent::ProcessFetchPartitionsRequest { request: fetch_partitions, connection_id }
+        }
+        Message::Partitions(partitions) => {
+            PbftHandlerEvent::ProcessPartitionsRequest { request: partitions, connection_id }
+        }
     }
 }
\ No newline at end of file
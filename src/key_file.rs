@@ -0,0 +1,28 @@
+use libp2p::identity::{Keypair, ed25519};
+
+// Ed25519 keypairs are stored in files so that replicas and clients keep their identities across runs
+
+pub fn load(path: &str) -> Result<Keypair, String> {
+    let mut bytes = std::fs::read(path).map_err(|e| format!("Failed to read the keypair from {}. error: {}", path, e))?;
+    let keypair = ed25519::Keypair::decode(&mut bytes).map_err(|e| format!("Failed to decode the keypair in {}. error: {:?}", path, e))?;
+    println!("[key_file::load] loaded the keypair from {}", path);
+    Ok(Keypair::Ed25519(keypair))
+}
+
+pub fn generate(path: &str) -> Result<Keypair, String> {
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create the directory for {}. error: {}", path, e))?;
+    }
+    let keypair = ed25519::Keypair::generate();
+    std::fs::write(path, &keypair.encode()[..]).map_err(|e| format!("Failed to store the keypair into {}. error: {}", path, e))?;
+    println!("[key_file::generate] generated a new keypair and stored it into {}", path);
+    Ok(Keypair::Ed25519(keypair))
+}
+
+pub fn load_or_generate(path: &str) -> Result<Keypair, String> {
+    if std::path::Path::new(path).exists() {
+        load(path)
+    } else {
+        generate(path)
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use crate::state_machine::StateMachine;
+use crate::message::digest;
+
+// The operations supported by the key-value store, encoded in `ClientRequest::operation` as
+// space-separated words:
+//   GET <key>
+//   PUT <key> <value>
+//   DELETE <key>
+//   CAS <key> <expected> <new>
+// Keys are single words. `<value>` and `<new>` are the rest of the line, so they may contain spaces.
+// `<expected>` may be double-quoted to contain spaces.
+#[derive(Debug, PartialEq)]
+enum Operation {
+    Get(String),
+    Put(String, String),
+    Delete(String),
+    Cas(String, String, String),
+}
+
+impl Operation {
+    fn parse(operation: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid operation: {:?}", operation);
+        let words: Vec<&str> = operation.trim().splitn(3, ' ').collect();
+        match words.as_slice() {
+            ["GET", key] => Ok(Operation::Get(key.to_string())),
+            ["PUT", key, value] if !value.is_empty() => Ok(Operation::Put(key.to_string(), value.to_string())),
+            ["DELETE", key] => Ok(Operation::Delete(key.to_string())),
+            ["CAS", key, rest] => {
+                let (expected, new) = split_expected(rest).ok_or_else(invalid)?;
+                Ok(Operation::Cas(key.to_string(), expected.to_string(), new.to_string()))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Splits `<expected> <new>`, where `<expected>` is either a word or double-quoted
+fn split_expected(s: &str) -> Option<(&str, &str)> {
+    let (expected, rest) = match s.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        }
+        None => {
+            let end = s.find(' ')?;
+            (&s[..end], &s[end..])
+        }
+    };
+    match rest.strip_prefix(' ') {
+        Some(new) if !new.is_empty() => Some((expected, new)),
+        _ => None,
+    }
+}
+
+// The number of partitions the entries are divided into
+const PARTITIONS: usize = 16;
+
+// A replicated key-value store. The entries are kept in a `BTreeMap` so that the partitions, and
+// therefore their digests, are the same on every replica that executed the same operations.
+pub struct KeyValueStore {
+    entries: BTreeMap<String, String>,
+}
+
+impl KeyValueStore {
+    pub fn new() -> Self {
+        Self { entries: BTreeMap::new() }
+    }
+}
+
+impl StateMachine for KeyValueStore {
+    fn execute(&mut self, operation: &str) -> String {
+        let operation = match Operation::parse(operation) {
+            Ok(operation) => operation,
+            Err(e) => return format!("ERROR: {}", e),
+        };
+        println!("[KeyValueStore::execute] operation: {:?}", operation);
+
+        match operation {
+            Operation::Get(key) => match self.entries.get(&key) {
+                Some(value) => value.clone(),
+                None => "NOT_FOUND".to_owned(),
+            },
+            Operation::Put(key, value) => {
+                self.entries.insert(key, value);
+                "OK".to_owned()
+            }
+            Operation::Delete(key) => match self.entries.remove(&key) {
+                Some(_) => "OK".to_owned(),
+                None => "NOT_FOUND".to_owned(),
+            },
+            Operation::Cas(key, expected, new) => match self.entries.get(&key) {
+                Some(value) if value == &expected => {
+                    self.entries.insert(key, new);
+                    "OK".to_owned()
+                }
+                Some(_) => "MISMATCH".to_owned(),
+                None => "NOT_FOUND".to_owned(),
+            },
+        }
+    }
+
+    fn partitions(&self) -> Vec<Vec<u8>> {
+        let mut partitions = vec![BTreeMap::new(); PARTITIONS];
+        for (key, value) in self.entries.iter() {
+            partitions[partition_of(key)].insert(key, value);
+        }
+        partitions.iter()
+            .map(|p| serde_json::to_vec(p).expect("BTreeMap<&String, &String> is always serializable"))
+            .collect()
+    }
+
+    fn restore_partitions(&mut self, partitions: &[(usize, Vec<u8>)]) -> Result<(), String> {
+        let mut restored = Vec::with_capacity(partitions.len());
+        for (index, partition) in partitions {
+            if *index >= PARTITIONS {
+                return Err(format!("Invalid partition: {}", index));
+            }
+            let entries: BTreeMap<String, String> = serde_json::from_slice(partition)
+                .map_err(|e| format!("Failed to restore the partition {}: {:?}", index, e))?;
+            if let Some(key) = entries.keys().find(|key| partition_of(key) != *index) {
+                return Err(format!("The key {:?} doesn't belong to the partition {}", key, index));
+            }
+            restored.push((*index, entries));
+        }
+
+        for (index, entries) in restored {
+            self.entries.retain(|key, _| partition_of(key) != index);
+            self.entries.extend(entries);
+        }
+        Ok(())
+    }
+}
+
+// The partition a key belongs to is determined by the digest of the key
+fn partition_of(key: &str) -> usize {
+    let digest = digest(key.as_bytes());
+    usize::from_str_radix(&digest[..4], 16).expect("The digest is a hex string") % PARTITIONS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Operation::parse("GET key"), Ok(Operation::Get("key".to_owned())));
+        assert_eq!(Operation::parse("PUT key value"), Ok(Operation::Put("key".to_owned(), "value".to_owned())));
+        assert_eq!(Operation::parse("DELETE key"), Ok(Operation::Delete("key".to_owned())));
+        assert_eq!(Operation::parse("CAS key old new"), Ok(Operation::Cas("key".to_owned(), "old".to_owned(), "new".to_owned())));
+        assert_eq!(Operation::parse(" GET key\n"), Ok(Operation::Get("key".to_owned())));
+
+        assert!(Operation::parse("").is_err());
+        assert!(Operation::parse("GET").is_err());
+        assert!(Operation::parse("GET key value").is_err());
+        assert!(Operation::parse("PUT key").is_err());
+        assert!(Operation::parse("CAS key old").is_err());
+        assert!(Operation::parse("get key").is_err());
+    }
+
+    #[test]
+    fn parse_values_with_spaces() {
+        assert_eq!(Operation::parse("PUT key hello  world"), Ok(Operation::Put("key".to_owned(), "hello  world".to_owned())));
+        assert_eq!(Operation::parse("CAS key old new value"), Ok(Operation::Cas("key".to_owned(), "old".to_owned(), "new value".to_owned())));
+        assert_eq!(Operation::parse("CAS key \"old value\" new value"), Ok(Operation::Cas("key".to_owned(), "old value".to_owned(), "new value".to_owned())));
+        assert_eq!(Operation::parse("CAS key \"\" new"), Ok(Operation::Cas("key".to_owned(), "".to_owned(), "new".to_owned())));
+
+        assert!(Operation::parse("PUT key ").is_err());
+        assert!(Operation::parse("CAS key \"old value new").is_err());
+        assert!(Operation::parse("CAS key \"old value\"new").is_err());
+        assert!(Operation::parse("CAS key \"old value\"").is_err());
+    }
+
+    #[test]
+    fn execute_values_with_spaces() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.execute("PUT key hello world"), "OK");
+        assert_eq!(store.execute("GET key"), "hello world");
+        assert_eq!(store.execute("CAS key \"hello world\" goodbye world"), "OK");
+        assert_eq!(store.execute("GET key"), "goodbye world");
+    }
+
+    #[test]
+    fn execute() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.execute("GET key"), "NOT_FOUND");
+        assert_eq!(store.execute("PUT key value"), "OK");
+        assert_eq!(store.execute("GET key"), "value");
+
+        assert_eq!(store.execute("CAS key other new"), "MISMATCH");
+        assert_eq!(store.execute("CAS key value new"), "OK");
+        assert_eq!(store.execute("GET key"), "new");
+        assert_eq!(store.execute("CAS missing value new"), "NOT_FOUND");
+
+        assert_eq!(store.execute("DELETE key"), "OK");
+        assert_eq!(store.execute("DELETE key"), "NOT_FOUND");
+        assert_eq!(store.execute("GET key"), "NOT_FOUND");
+
+        assert!(store.execute("INCR key").starts_with("ERROR"));
+    }
+
+    #[test]
+    fn digest_is_deterministic() {
+        let mut a = KeyValueStore::new();
+        let mut b = KeyValueStore::new();
+        for i in 0..100 {
+            a.execute(&format!("PUT key{} value{}", i, i));
+        }
+        for i in (0..100).rev() {
+            b.execute(&format!("PUT key{} value{}", i, i));
+        }
+
+        assert_eq!(a.snapshot(), b.snapshot());
+        assert_eq!(a.digest(), b.digest());
+
+        b.execute("PUT key0 other");
+        assert_ne!(a.digest(), b.digest());
+    }
+
+    #[test]
+    fn restore() {
+        let mut source = KeyValueStore::new();
+        for i in 0..100 {
+            source.execute(&format!("PUT key{} value{}", i, i));
+        }
+        let mut target = KeyValueStore::new();
+        target.execute("PUT stale value");
+
+        target.restore(&source.snapshot()).unwrap();
+        assert_eq!(target.digest(), source.digest());
+        assert_eq!(target.execute("GET key0"), "value0");
+        assert_eq!(target.execute("GET stale"), "NOT_FOUND");
+    }
+
+    #[test]
+    fn restore_invalid_snapshot() {
+        let mut store = KeyValueStore::new();
+        store.execute("PUT key value");
+
+        assert!(store.restore(b"garbage").is_err());
+        assert_eq!(store.execute("GET key"), "value");
+    }
+
+    #[test]
+    fn partitions_are_deterministic() {
+        let mut a = KeyValueStore::new();
+        let mut b = KeyValueStore::new();
+        for i in 0..100 {
+            a.execute(&format!("PUT key{} value{}", i, i));
+        }
+        for i in (0..100).rev() {
+            b.execute(&format!("PUT key{} value{}", i, i));
+        }
+
+        assert_eq!(a.partitions().len(), PARTITIONS);
+        assert_eq!(a.partitions(), b.partitions());
+    }
+
+    #[test]
+    fn restore_partitions() {
+        let mut source = KeyValueStore::new();
+        for i in 0..100 {
+            source.execute(&format!("PUT key{} value{}", i, i));
+        }
+        let mut target = KeyValueStore::new();
+        target.execute("PUT key0 stale");
+
+        let partitions: Vec<(usize, Vec<u8>)> = source.partitions().into_iter().enumerate().collect();
+        target.restore_partitions(&partitions).unwrap();
+        assert_eq!(target.partitions(), source.partitions());
+        assert_eq!(target.execute("GET key0"), "value0");
+    }
+
+    #[test]
+    fn restore_partitions_leaves_the_other_partitions() {
+        let mut source = KeyValueStore::new();
+        source.execute("PUT key value");
+        let mut target = KeyValueStore::new();
+        target.execute("PUT key stale");
+        let index = partition_of("key");
+        let other = (index + 1) % PARTITIONS;
+
+        target.restore_partitions(&[(other, source.partitions()[other].clone())]).unwrap();
+        assert_eq!(target.execute("GET key"), "stale");
+
+        target.restore_partitions(&[(index, source.partitions()[index].clone())]).unwrap();
+        assert_eq!(target.execute("GET key"), "value");
+    }
+
+    #[test]
+    fn restore_invalid_partitions() {
+        let mut store = KeyValueStore::new();
+        store.execute("PUT key value");
+        let index = partition_of("key");
+        let partition = store.partitions()[index].clone();
+
+        assert!(store.restore_partitions(&[(PARTITIONS, partition.clone())]).is_err());
+        assert!(store.restore_partitions(&[((index + 1) % PARTITIONS, partition)]).is_err());
+        assert!(store.restore_partitions(&[(index, b"garbage".to_vec())]).is_err());
+        // the partitions are validated before any of them is restored
+        assert_eq!(store.execute("GET key"), "value");
+    }
+}
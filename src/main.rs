@@ -1,13 +1,22 @@
 use crate::client_handler::ClientHandler;
 use std::sync::{Arc, RwLock};
-use crate::node_type::NodeType;
 use libp2p::{PeerId, build_development_transport, Swarm};
-use libp2p::identity::Keypair;
 use crate::network_behaviour_composer::NetworkBehaviourComposer;
 use futures::Async;
 use futures::stream::Stream;
 use std::collections::VecDeque;
-use crate::behavior::Pbft;
+use crate::behavior::{Pbft, CHECKPOINT_PERIOD};
+use crate::quorum::Quorum;
+use crate::water_mark::WaterMarks;
+use crate::kv_store::KeyValueStore;
+use crate::authenticator::AuthenticationMode;
+use crate::config::NetworkConfig;
+use crate::membership::Membership;
+use crate::batch::Batcher;
+use crate::pipeline::Pipeline;
+use crate::wal::{Wal, SyncPolicy};
+use crate::state::State;
+use std::time::Duration;
 
 mod network_behaviour_composer;
 mod handler;
@@ -18,42 +27,148 @@ mod state;
 mod node_type;
 mod message;
 mod view;
+mod timer;
+mod quorum;
+mod water_mark;
+mod state_machine;
+mod kv_store;
+mod membership;
+mod authenticator;
+mod client;
+mod config;
+mod key_file;
+mod batch;
+mod pipeline;
+mod wal;
+mod state_transfer;
+mod partition_tree;
+mod retransmission;
+mod error;
+
+// The static membership of the cluster
+const NETWORK_CONFIG: &str = "network.json";
+// The size of the window between the low and high water marks (`PBFT_WATER_MARK_WINDOW`). It must be
+// at least the checkpoint period, as the low water mark only advances when a checkpoint becomes stable.
+const WATER_MARK_WINDOW: u64 = 200;
+// The primary sends a pre-prepare when this many requests are pending (`PBFT_MAX_BATCH_SIZE`)
+const MAX_BATCH_SIZE: usize = 10;
+// or when the oldest pending request has waited this many milliseconds (`PBFT_MAX_BATCH_DELAY`)
+const MAX_BATCH_DELAY: u64 = 50;
+// The number of sequence numbers the primary keeps in flight (`PBFT_PIPELINE_WINDOW`). It must not
+// exceed the water mark window.
+const PIPELINE_WINDOW: u64 = 20;
+// The write-ahead logs of the replicas are stored in this directory
+const WAL_DIR: &str = "wal";
 
 fn main() {
     println!("Hello, PBFT!");
     let cli_args: Vec<String> = std::env::args().collect();
     println!("[main] cli_args: {:?}", cli_args);
-    let node_type = determine_node_type(&cli_args).expect("Usage: $ pbft [primary]");
-    println!("[main] node_type: {:?}", node_type);
+    if cli_args.get(1).map(String::as_str) == Some("client") {
+        run_client(&cli_args);
+        return;
+    }
+    if cli_args.get(1).map(String::as_str) == Some("keygen") {
+        run_keygen(&cli_args);
+        return;
+    }
+
+    let replica_id: usize = cli_args.get(1)
+        .and_then(|replica_id| replica_id.parse().ok())
+        .expect("Usage: $ pbft <replica id>");
+    let config = NetworkConfig::load(NETWORK_CONFIG)
+        .unwrap_or_else(|e| panic!("{}. Generate the keypairs with `$ pbft keygen <replica id>` and put the public keys into {}", e, NETWORK_CONFIG));
+    let replica = config.replica(replica_id).expect("The replica id is not in the membership").clone();
+    println!("[main] replica: {:?}", replica);
+
+    // Commit messages are authenticated with MAC authenticators if `PBFT_AUTHENTICATION=authenticators`
+    let authentication = match std::env::var("PBFT_AUTHENTICATION") {
+        Ok(mode) => mode.parse::<AuthenticationMode>().unwrap(),
+        Err(_) => AuthenticationMode::Signatures,
+    };
+    println!("[main] authentication: {:?}", authentication);
+
+    // The protocol state is recovered from the write-ahead log. The records are synced to the disk
+    // after every append unless `PBFT_WAL_SYNC` is `interval:<millis>` or `never`.
+    let sync_policy = match std::env::var("PBFT_WAL_SYNC") {
+        Ok(policy) => policy.parse::<SyncPolicy>().unwrap(),
+        Err(_) => SyncPolicy::Always,
+    };
+    let (wal, wal_records) = Wal::open(&format!("{}/replica-{}.log", WAL_DIR, replica_id), sync_policy).unwrap();
+
+    let max_batch_size: usize = env_var("PBFT_MAX_BATCH_SIZE", MAX_BATCH_SIZE);
+    let max_batch_delay = Duration::from_millis(env_var("PBFT_MAX_BATCH_DELAY", MAX_BATCH_DELAY));
+    println!("[main] max_batch_size: {}, max_batch_delay: {:?}", max_batch_size, max_batch_delay);
+    let water_mark_window = env_var("PBFT_WATER_MARK_WINDOW", WATER_MARK_WINDOW);
+    if water_mark_window < CHECKPOINT_PERIOD {
+        panic!("[main] The water mark window must be at least the checkpoint period. water_mark_window: {}, checkpoint_period: {}", water_mark_window, CHECKPOINT_PERIOD);
+    }
+    let pipeline_window = env_var("PBFT_PIPELINE_WINDOW", PIPELINE_WINDOW);
+    if pipeline_window > water_mark_window {
+        panic!("[main] The pipeline window must not exceed the water mark window. pipeline_window: {}, water_mark_window: {}", pipeline_window, water_mark_window);
+    }
+    println!("[main] water_mark_window: {}, pipeline_window: {}", water_mark_window, pipeline_window);
 
     let client_requests = Arc::new(RwLock::new(VecDeque::new()));
     let client_replies = Arc::new(RwLock::new(VecDeque::new()));
 
     let mut client_request_handler = ClientHandler::new(
-        node_type,
+        replica.client_port(),
         client_requests.clone(),
         client_replies.clone(),
     );
 
-    let local_key = Keypair::generate_ed25519();
+    let local_key = key_file::load(&key_file_path(replica_id))
+        .unwrap_or_else(|e| panic!("{}. Generate the keypair with `$ pbft keygen {}`", e, replica_id));
     let local_peer_id = PeerId::from(local_key.public());
+    println!("[main] local_peer_id: {:?}", local_peer_id);
+    if local_peer_id != replica.peer_id() {
+        panic!("[main] The keypair doesn't match with the public key in {}. replica_id: {}", NETWORK_CONFIG, replica_id);
+    }
+
+    // The replicas in the membership are dialed directly, so the replica works without mDNS
+    let mdns = match libp2p::mdns::Mdns::new() {
+        Ok(mdns) => Some(mdns),
+        Err(e) => {
+            eprintln!("[main] mDNS is not available. error: {:?}", e);
+            None
+        }
+    };
 
     let transport = build_development_transport(local_key.clone());
     let mut swarm = Swarm::new(
         transport,
         NetworkBehaviourComposer::new(
-            libp2p::mdns::Mdns::new().expect("Failed to create mDNS service"),
-            Pbft::new(local_key, client_replies.clone()),
+            mdns,
+            Pbft::new(
+                local_key,
+                client_replies.clone(),
+                Quorum::new(config.replicas().len()),
+                WaterMarks::new(water_mark_window),
+                Box::new(KeyValueStore::new()),
+                authentication,
+                Membership::new(config.replicas().iter().map(|r| r.peer_id()).collect()),
+                Batcher::new(max_batch_size, max_batch_delay),
+                Pipeline::new(pipeline_window),
+                State::recover(wal, wal_records),
+            ),
         ),
         local_peer_id
     );
 
-    Swarm::listen_on(&mut swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+    Swarm::listen_on(&mut swarm, replica.address().unwrap()).unwrap();
+
+    // The replica dials the other replicas in the membership, as mDNS may not be available
+    for r in config.replicas().iter().filter(|r| r.replica_id() != replica_id) {
+        swarm.pbft.add_peer(&r.peer_id(), &r.address().unwrap());
+    }
 
     let mut listening = false;
     tokio::run(futures::future::poll_fn(move || {
         loop {
-            if let Some(client_request) = client_requests.write().unwrap().pop_front() {
+            // All the pending requests are handed over so that the primary can batch them
+            let pending: Vec<_> = client_requests.write().unwrap().drain(..).collect();
+            for client_request in pending {
                 swarm.pbft.add_client_request(client_request);
             }
 
@@ -75,20 +190,38 @@ fn main() {
     }));
 }
 
-fn determine_node_type(args: &Vec<String>) -> Result<NodeType, ()> {
-    match args.len() {
-        1 => Ok(NodeType::Backup),
-        2 => {
-            if let Some(node_type) = args.get(1) {
-                if node_type == "primary" {
-                    return Ok(NodeType::Primary)
-                } else {
-                    panic!(format!("[main::determine_node_type] Invalid node_type: {:?}", node_type));
-                }
-            } {
-                unreachable!();
-            }
-        },
-        _ => Err(()),
+fn run_client(args: &[String]) {
+    let usage = "Usage: $ pbft client <replica address> <reply address> <operation>";
+    if args.len() < 5 {
+        panic!("{}", usage);
+    }
+    let replica = args[2].parse().expect(usage);
+    let reply_address = args[3].parse().expect(usage);
+    client::run(replica, reply_address, args[4..].join(" "));
+}
+
+// Generates the keypair of a replica. The printed public key goes into `network.json`.
+fn run_keygen(args: &[String]) {
+    let replica_id: usize = args.get(2)
+        .and_then(|replica_id| replica_id.parse().ok())
+        .expect("Usage: $ pbft keygen <replica id>");
+    let keypair = key_file::generate(&key_file_path(replica_id)).unwrap();
+    println!("peer_id: {}", PeerId::from(keypair.public()).to_base58());
+    println!("public_key: {}", config::encode_public_key(keypair.public()));
+}
+
+fn key_file_path(replica_id: usize) -> String {
+    format!("keys/replica-{}.key", replica_id)
+}
+
+// The value of the environment variable, or the default if it is not set
+fn env_var<T>(name: &str, default: T) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Debug,
+{
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|e| panic!("[main] Invalid {}: {:?}, error: {:?}", name, value, e)),
+        Err(_) => default,
     }
 }
@@ -0,0 +1,34 @@
+use libp2p::PeerId;
+
+// The replicas in the cluster. The replica id of a replica is its index in the list.
+pub struct Membership {
+    replicas: Vec<PeerId>,
+}
+
+impl Membership {
+    pub fn new(replicas: Vec<PeerId>) -> Self {
+        println!("[Membership::new] replicas: {:?}", replicas.iter().map(|p| p.to_base58()).collect::<Vec<_>>());
+        Self { replicas }
+    }
+
+    pub fn replicas(&self) -> &Vec<PeerId> {
+        &self.replicas
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.replicas.contains(peer_id)
+    }
+
+    pub fn replica_id(&self, peer_id: &PeerId) -> Option<usize> {
+        self.replicas.iter().position(|p| p == peer_id)
+    }
+
+    pub fn is_member(&self, replica: &str) -> bool {
+        self.replicas.iter().any(|p| p.to_base58() == replica)
+    }
+
+    // The primary of a view is replica _p_ such that _p = v mod |R|_
+    pub fn primary(&self, view: u64) -> &PeerId {
+        &self.replicas[(view % self.replicas.len() as u64) as usize]
+    }
+}
@@ -1,26 +1,66 @@
 use serde::{Serialize, Deserialize, Serializer};
+use serde::de::DeserializeOwned;
 use serde::ser::SerializeStruct;
 use blake2::{Blake2b, Digest};
 use libp2p::PeerId;
+use libp2p::identity::{Keypair, PublicKey};
 use std::net::SocketAddr;
+use std::collections::{HashMap, BTreeMap};
+use std::convert::TryFrom;
+use crate::authenticator::{mac, verify_mac};
+use crate::error::PbftError;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
     ClientRequest(ClientRequest),
-    PrePrepare(PrePrepare),
-    Prepare(Prepare),
-    Commit(Commit),
+    PrePrepare(Signed<PrePrepare>),
+    Prepare(Envelope<Prepare>),
+    Commit(Envelope<Commit>),
+    ViewChange(Signed<ViewChange>),
+    NewView(Signed<NewView>),
+    Checkpoint(Signed<Checkpoint>),
+    NewKey(Signed<NewKey>),
+    FetchState(Signed<FetchState>),
+    StateSnapshot(Signed<StateSnapshot>),
+    FetchPartitions(Signed<FetchPartitions>),
+    Partitions(Signed<Partitions>),
 }
 
-impl From<Vec<u8>> for Message {
-    fn from(item: Vec<u8>) -> Self {
-        serde_json::from_str(&String::from_utf8(item).unwrap()).unwrap()
+impl Message {
+    // The id of the message if its delivery is tracked
+    pub fn id(&self) -> Option<MessageId> {
+        match self {
+            Message::PrePrepare(pre_prepare) => Some(MessageId::PrePrepare(pre_prepare.message().view(), pre_prepare.message().sequence_number())),
+            Message::Prepare(prepare) => Some(MessageId::Prepare(prepare.message().view(), prepare.message().sequence_number())),
+            Message::Commit(commit) => Some(MessageId::Commit(commit.message().view(), commit.message().sequence_number())),
+            _ => None,
+        }
     }
 }
 
-impl From<String> for Message {
-    fn from(s: String) -> Self {
-        serde_json::from_str(&s).unwrap()
+// Identifies a pre-prepare, prepare or commit message by its view and sequence number, so that the
+// responses from the peers can be matched to the message
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageId {
+    PrePrepare(u64, u64),
+    Prepare(u64, u64),
+    Commit(u64, u64),
+}
+
+impl MessageId {
+    pub fn sequence_number(&self) -> u64 {
+        match self {
+            MessageId::PrePrepare(_, n) | MessageId::Prepare(_, n) | MessageId::Commit(_, n) => *n,
+        }
+    }
+}
+
+// The bytes are received from peers and clients, so a malformed message is reported as an error
+impl TryFrom<&[u8]> for Message {
+    type Error = PbftError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        serde_json::from_slice(bytes).map_err(|e| PbftError::MalformedMessage(e.to_string()))
     }
 }
 
@@ -30,14 +70,258 @@ impl std::fmt::Display for Message {
     }
 }
 
+// All messages exchanged between replicas are signed by the sender so that a faulty replica cannot
+// forge messages on behalf of others. The message is carried as the bytes that were signed and is
+// decoded from them on receipt, so that the signature of a message with fields this replica doesn't
+// know still verifies.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "SignedPayload", bound(deserialize = "T: DeserializeOwned"))]
+pub struct Signed<T> {
+    message: T,
+    // the encoding of the message that the signature is computed over
+    payload: Vec<u8>,
+    // the replica that signed the message
+    replica: String,
+    // the public key of the replica in the protobuf encoding
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+// The encoding of `Signed` on the wire, without the decoded message
+#[derive(Deserialize)]
+struct SignedPayload {
+    payload: Vec<u8>,
+    replica: String,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl<T: DeserializeOwned> TryFrom<SignedPayload> for Signed<T> {
+    type Error = serde_json::Error;
+
+    fn try_from(signed: SignedPayload) -> Result<Self, Self::Error> {
+        Ok(Self {
+            message: serde_json::from_slice(&signed.payload)?,
+            payload: signed.payload,
+            replica: signed.replica,
+            public_key: signed.public_key,
+            signature: signed.signature,
+        })
+    }
+}
+
+impl<T> Serialize for Signed<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Signed", 4)?;
+        state.serialize_field("payload", &self.payload)?;
+        state.serialize_field("replica", &self.replica)?;
+        state.serialize_field("public_key", &self.public_key)?;
+        state.serialize_field("signature", &self.signature)?;
+        state.end()
+    }
+}
+
+impl<T: Serialize> Signed<T> {
+    pub fn new(message: T, keypair: &Keypair) -> Self {
+        let payload = serde_json::to_vec(&message).unwrap();
+        let signature = keypair.sign(&payload).expect("Failed to sign the message");
+        let public_key = keypair.public();
+        Self {
+            message,
+            payload,
+            replica: PeerId::from_public_key(public_key.clone()).to_base58(),
+            public_key: public_key.into_protobuf_encoding(),
+            signature,
+        }
+    }
+}
+
+impl<T> Signed<T> {
+    // Checks that the public key belongs to the replica and the signature is valid for the message
+    pub fn verify(&self) -> Result<(), String> {
+        verify_signature(&self.replica, &self.public_key, &self.payload, &self.signature)
+    }
+}
+
+// Checks that the public key belongs to the signer (a replica or a client, whose ids are derived from
+// their public keys) and the signature is valid for the bytes
+fn verify_signature(signer: &str, public_key: &[u8], bytes: &[u8], signature: &[u8]) -> Result<(), String> {
+    let public_key = PublicKey::from_protobuf_encoding(public_key)
+        .map_err(|e| format!("Failed to decode the public key. signer: {}, error: {:?}", signer, e))?;
+
+    if PeerId::from_public_key(public_key.clone()).to_base58() != signer {
+        return Err(format!("The public key doesn't match with the signer. signer: {}", signer));
+    }
+
+    if !public_key.verify(bytes, signature) {
+        return Err(format!("The signature is invalid. signer: {}", signer));
+    }
+    Ok(())
+}
+
+impl<T> Signed<T> {
+    pub fn message(&self) -> &T {
+        &self.message
+    }
+
+    pub fn into_message(self) -> T {
+        self.message
+    }
+
+    pub fn replica(&self) -> &String {
+        &self.replica
+    }
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Signed<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (signed by {})", self.message, self.replica)
+    }
+}
+
+// An authenticator is a vector of MACs, one per replica, each computed with the session key the
+// sender shares with that replica. Unlike a signature, a receiver can only verify its own entry. As
+// with `Signed`, the message is carried as the bytes that the MACs are computed over.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "AuthenticatedPayload", bound(deserialize = "T: DeserializeOwned"))]
+pub struct Authenticated<T> {
+    message: T,
+    // the encoding of the message that the MACs are computed over
+    payload: Vec<u8>,
+    // the replica that authenticated the message
+    replica: String,
+    // keyed by the replica that can verify the MAC
+    macs: BTreeMap<String, Vec<u8>>,
+}
+
+// The encoding of `Authenticated` on the wire, without the decoded message
+#[derive(Deserialize)]
+struct AuthenticatedPayload {
+    payload: Vec<u8>,
+    replica: String,
+    macs: BTreeMap<String, Vec<u8>>,
+}
+
+impl<T: DeserializeOwned> TryFrom<AuthenticatedPayload> for Authenticated<T> {
+    type Error = serde_json::Error;
+
+    fn try_from(authenticated: AuthenticatedPayload) -> Result<Self, Self::Error> {
+        Ok(Self {
+            message: serde_json::from_slice(&authenticated.payload)?,
+            payload: authenticated.payload,
+            replica: authenticated.replica,
+            macs: authenticated.macs,
+        })
+    }
+}
+
+impl<T> Serialize for Authenticated<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Authenticated", 3)?;
+        state.serialize_field("payload", &self.payload)?;
+        state.serialize_field("replica", &self.replica)?;
+        state.serialize_field("macs", &self.macs)?;
+        state.end()
+    }
+}
+
+impl<T: Serialize> Authenticated<T> {
+    pub fn new(message: T, replica: &PeerId, session_keys: &HashMap<PeerId, Vec<u8>>) -> Self {
+        let payload = serde_json::to_vec(&message).unwrap();
+        let macs = session_keys.iter()
+            .map(|(peer_id, key)| (peer_id.to_base58(), mac(key, &payload)))
+            .collect();
+        Self { message, payload, replica: replica.to_base58(), macs }
+    }
+}
+
+impl<T> Authenticated<T> {
+    // Checks the entry for the `receiver` with the session key it shares with the sender
+    pub fn verify(&self, receiver: &PeerId, session_key: &[u8]) -> Result<(), String> {
+        let code = match self.macs.get(&receiver.to_base58()) {
+            Some(code) => code,
+            None => return Err(format!("The authenticator has no MAC for the replica. replica: {}, receiver: {:?}", self.replica, receiver)),
+        };
+
+        if !verify_mac(session_key, &self.payload, code) {
+            return Err(format!("The MAC is invalid. replica: {}", self.replica));
+        }
+        Ok(())
+    }
+}
+
+// Commit messages are either signed or authenticated with MACs, depending on the authentication
+// mode of the deployment. Prepare messages are always signed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: DeserializeOwned"))]
+pub enum Envelope<T> {
+    Signed(Signed<T>),
+    Authenticated(Authenticated<T>),
+}
+
+impl<T> Envelope<T> {
+    pub fn message(&self) -> &T {
+        match self {
+            Envelope::Signed(signed) => &signed.message,
+            Envelope::Authenticated(authenticated) => &authenticated.message,
+        }
+    }
+
+    pub fn into_message(self) -> T {
+        match self {
+            Envelope::Signed(signed) => signed.message,
+            Envelope::Authenticated(authenticated) => authenticated.message,
+        }
+    }
+
+    pub fn replica(&self) -> &String {
+        match self {
+            Envelope::Signed(signed) => &signed.replica,
+            Envelope::Authenticated(authenticated) => &authenticated.replica,
+        }
+    }
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Envelope<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (authenticated by {})", self.message(), self.replica())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ClientRequest {
     operation: String,
     timestamp: u64,
+    // the id of the client, derived from its public key
+    client_id: String,
+    // the address the replicas send the replies to
     client: SocketAddr,
+    // the public key of the client in the protobuf encoding
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
 }
 
 impl ClientRequest {
+    pub fn new(operation: String, timestamp: u64, client: SocketAddr, keypair: &Keypair) -> Self {
+        let public_key = keypair.public();
+        let mut client_request = Self {
+            operation,
+            timestamp,
+            client_id: PeerId::from_public_key(public_key.clone()).to_base58(),
+            client,
+            public_key: public_key.into_protobuf_encoding(),
+            signature: Vec::new(),
+        };
+        client_request.signature = keypair.sign(&client_request.signed_bytes()).expect("Failed to sign the request");
+        client_request
+    }
+
     pub fn operation(&self) -> String {
         self.operation.clone()
     }
@@ -46,28 +330,53 @@ impl ClientRequest {
         self.timestamp
     }
 
+    pub fn client_id(&self) -> &String {
+        &self.client_id
+    }
+
     pub fn client(&self) -> SocketAddr {
         self.client.clone()
     }
+
+    // The digest is computed over a canonical encoding of the operation, the timestamp and the client
+    // id so that it identifies the request, not only the operation
+    pub fn digest(&self) -> String {
+        digest(&serde_json::to_vec(&(&self.operation, self.timestamp, &self.client_id)).unwrap())
+    }
+
+    // The client signs everything in the request except the public key and the signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.operation, self.timestamp, &self.client_id, &self.client)).unwrap()
+    }
+
+    // A request can't be forged or replayed under another client's identity as the client id is
+    // derived from the public key the request is signed with
+    pub fn verify(&self) -> Result<(), String> {
+        verify_signature(&self.client_id, &self.public_key, &self.signed_bytes(), &self.signature)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ClientReply {
     view: u64,
     timestamp: u64,
-    client: SocketAddr, // Is this correct as `c`?
+    // `c` in the reply
+    client_id: String,
+    // the address the reply is sent to
+    client: SocketAddr,
     peer_id: PeerId,
     result: String,
 }
 
 impl ClientReply {
-    pub fn new(peer_id: PeerId, client_request: &ClientRequest, commit: &Commit) -> Self {
+    pub fn new(peer_id: PeerId, client_request: &ClientRequest, commit: &Commit, result: String) -> Self {
         Self {
             view: commit.view(),
             timestamp: client_request.timestamp(),
+            client_id: client_request.client_id().clone(),
             client: client_request.client(),
             peer_id,
-            result: "awesome!".to_owned(), // TODO
+            result,
         }
     }
 }
@@ -77,9 +386,61 @@ impl ClientReply {
         self.timestamp
     }
 
+    pub fn client_id(&self) -> &String {
+        &self.client_id
+    }
+
     pub fn client_address(&self) -> SocketAddr {
         self.client.clone()
     }
+
+    pub fn to_cached(&self) -> CachedReply {
+        CachedReply {
+            view: self.view,
+            timestamp: self.timestamp,
+            client_id: self.client_id.clone(),
+            client: self.client,
+            result: self.result.clone(),
+        }
+    }
+
+    // The reply is re-sent by this replica, so it is attributed to this replica
+    pub fn from_cached(peer_id: PeerId, cached: CachedReply) -> Self {
+        Self {
+            view: cached.view,
+            timestamp: cached.timestamp,
+            client_id: cached.client_id,
+            client: cached.client,
+            peer_id,
+            result: cached.result,
+        }
+    }
+}
+
+// The last reply to a client without the replica that sent it. Non-faulty replicas cache the same
+// replies, so they are covered by the checkpoint digest and transferred with the service state. The
+// view is not covered, as replicas may have executed the same request in different views.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedReply {
+    view: u64,
+    timestamp: u64,
+    client_id: String,
+    client: SocketAddr,
+    result: String,
+}
+
+impl CachedReply {
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn client_id(&self) -> &String {
+        &self.client_id
+    }
+
+    pub fn result(&self) -> &String {
+        &self.result
+    }
 }
 
 impl Serialize for ClientReply {
@@ -87,9 +448,10 @@ impl Serialize for ClientReply {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("ClientReply", 4)?;
+        let mut state = serializer.serialize_struct("ClientReply", 5)?;
         state.serialize_field("view", &self.view)?;
         state.serialize_field("timestamp", &self.timestamp)?;
+        state.serialize_field("client_id", &self.client_id)?;
         state.serialize_field("peer_id", &self.peer_id.to_string())?;
         state.serialize_field("result", &self.result)?;
         state.end()
@@ -108,10 +470,10 @@ pub struct PrePrepare {
     view: u64,
     // sequence number for pre-prepare messages
     sequence_number: u64,
-    // client message's digest
+    // the digest of the batch of client messages
     digest: String,
-    // client message
-    message: ClientRequest,
+    // the batch of client messages (empty for a null request issued by a new primary)
+    client_requests: Vec<ClientRequest>,
 }
 
 impl PrePrepare {
@@ -127,20 +489,31 @@ impl PrePrepare {
         &self.digest
     }
 
-    pub fn client_reqeust(&self) -> &ClientRequest {
-        &self.message
+    pub fn client_requests(&self) -> &Vec<ClientRequest> {
+        &self.client_requests
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.client_requests.is_empty()
     }
 
-    pub fn from(view: u64, n: u64, client_request: ClientRequest) -> Self {
-        let digest = digest(client_request.operation.as_bytes());
-        Self { view, sequence_number: n, digest, message: client_request }
+    // The primary batches client requests so that a single run of the protocol orders all of them
+    pub fn from(view: u64, n: u64, client_requests: Vec<ClientRequest>) -> Self {
+        let digest = batch_digest(&client_requests);
+        Self { view, sequence_number: n, digest, client_requests }
+    }
+
+    // The null request goes through the protocol like other requests, but its execution is a no-op
+    pub fn null(view: u64, n: u64) -> Self {
+        Self::from(view, n, Vec::new())
     }
 
     pub fn validate_digest(&self) -> Result<(), String> {
-        if self.digest == digest(&self.message.operation.as_bytes()) {
+        let expected = batch_digest(&self.client_requests);
+        if self.digest == expected {
             Ok(())
         } else {
-            Err(format!("The digest is not matched with message. digest: {}, message.operation: {}", self.digest, self.message.operation))
+            Err(format!("The digest is not matched with message. digest: {}, expected: {}", self.digest, expected))
         }
     }
 }
@@ -169,6 +542,11 @@ impl PrePrepareSequence {
     pub fn value(&self) -> u64 {
         self.value
     }
+
+    pub fn reset(&mut self, value: u64) {
+        println!("[PrePrepareSequence::reset] value has been reset from {} to {}", self.value, value);
+        self.value = value;
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -206,7 +584,13 @@ impl std::fmt::Display for Prepare {
     }
 }
 
-fn digest(message: &[u8]) -> String {
+// The digest of a batch covers the digests of all the requests in the batch, in order
+fn batch_digest(client_requests: &[ClientRequest]) -> String {
+    let digests: Vec<String> = client_requests.iter().map(|r| r.digest()).collect();
+    digest(&serde_json::to_vec(&digests).unwrap())
+}
+
+pub fn digest(message: &[u8]) -> String {
     let hash = Blake2b::digest(message);
     format!("{:x}", hash)
 }
@@ -226,6 +610,10 @@ impl Commit {
     pub fn sequence_number(&self) -> u64 {
         self.sequence_number
     }
+
+    pub fn digest(&self) -> &String {
+        &self.digest
+    }
 }
 
 impl From<Prepare> for Commit {
@@ -243,3 +631,391 @@ impl std::fmt::Display for Commit {
         write!(f, "{}", serde_json::to_string(self).unwrap())
     }
 }
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PreparedCertificate {
+    // the pre-prepare message that has been prepared
+    pre_prepare: Signed<PrePrepare>,
+    // matching prepare messages from different replicas
+    prepares: Vec<Envelope<Prepare>>,
+}
+
+impl PreparedCertificate {
+    pub fn new(pre_prepare: Signed<PrePrepare>, prepares: Vec<Envelope<Prepare>>) -> Self {
+        Self { pre_prepare, prepares }
+    }
+
+    pub fn pre_prepare(&self) -> &Signed<PrePrepare> {
+        &self.pre_prepare
+    }
+
+    pub fn prepares(&self) -> &Vec<Envelope<Prepare>> {
+        &self.prepares
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ViewChange {
+    // the view the replica is moving to
+    new_view: u64,
+    // the sequence number of the last stable checkpoint known to the replica
+    last_stable_checkpoint: u64,
+    // `C` is a set of 2f + 1 valid checkpoint messages proving the correctness of the checkpoint
+    checkpoint_proof: Vec<Signed<Checkpoint>>,
+    // `P` is a set containing a prepared certificate for each request that prepared at the replica
+    prepared_certificates: Vec<PreparedCertificate>,
+}
+
+impl ViewChange {
+    pub fn new(
+        new_view: u64,
+        last_stable_checkpoint: u64,
+        checkpoint_proof: Vec<Signed<Checkpoint>>,
+        prepared_certificates: Vec<PreparedCertificate>,
+    ) -> Self {
+        Self { new_view, last_stable_checkpoint, checkpoint_proof, prepared_certificates }
+    }
+
+    pub fn new_view(&self) -> u64 {
+        self.new_view
+    }
+
+    pub fn last_stable_checkpoint(&self) -> u64 {
+        self.last_stable_checkpoint
+    }
+
+    pub fn checkpoint_proof(&self) -> &Vec<Signed<Checkpoint>> {
+        &self.checkpoint_proof
+    }
+
+    pub fn prepared_certificates(&self) -> &Vec<PreparedCertificate> {
+        &self.prepared_certificates
+    }
+}
+
+impl std::fmt::Display for ViewChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewView {
+    view: u64,
+    // `V` is a set containing the valid view-change messages received by the primary
+    view_changes: Vec<Signed<ViewChange>>,
+    // `O` is a set of pre-prepare messages re-issued in the new view, signed by the new primary
+    pre_prepares: Vec<Signed<PrePrepare>>,
+}
+
+impl NewView {
+    pub fn new(view: u64, view_changes: Vec<Signed<ViewChange>>, pre_prepares: Vec<Signed<PrePrepare>>) -> Self {
+        Self { view, view_changes, pre_prepares }
+    }
+
+    pub fn view(&self) -> u64 {
+        self.view
+    }
+
+    pub fn view_changes(&self) -> &Vec<Signed<ViewChange>> {
+        &self.view_changes
+    }
+
+    pub fn pre_prepares(&self) -> &Vec<Signed<PrePrepare>> {
+        &self.pre_prepares
+    }
+
+    // The primary determines the sequence number _min-s_ of the latest stable checkpoint in _V_ and
+    // the highest sequence number _max-s_ in a prepare message in _V_. For each sequence number _n_
+    // between _min-s_ and _max-s_, it creates a new pre-prepare message for view _v_ + 1:
+    //   - if there is at least one set in the _P_ component of some view-change message in _V_ with
+    //     sequence number _n_, the pre-prepare carries the request in the set with the highest view
+    //   - otherwise, the pre-prepare carries the null request
+    pub fn compute_pre_prepares(view: u64, view_changes: &[Signed<ViewChange>]) -> Vec<PrePrepare> {
+        let min_s = Self::min_s(view_changes);
+        let mut certificates: HashMap<u64, &PrePrepare> = HashMap::new();
+        for view_change in view_changes {
+            for certificate in view_change.message().prepared_certificates() {
+                let pre_prepare = certificate.pre_prepare().message();
+                if pre_prepare.sequence_number() <= min_s {
+                    continue;
+                }
+                let replace = match certificates.get(&pre_prepare.sequence_number()) {
+                    Some(stored) => stored.view() < pre_prepare.view(),
+                    None => true,
+                };
+                if replace {
+                    certificates.insert(pre_prepare.sequence_number(), pre_prepare);
+                }
+            }
+        }
+        let max_s = certificates.keys().max().cloned().unwrap_or(min_s);
+
+        ((min_s + 1)..=max_s).map(|n| {
+            match certificates.get(&n) {
+                Some(pre_prepare) => PrePrepare {
+                    view,
+                    sequence_number: n,
+                    digest: pre_prepare.digest().clone(),
+                    client_requests: pre_prepare.client_requests.clone(),
+                },
+                None => PrePrepare::null(view, n),
+            }
+        }).collect()
+    }
+
+    // The sequence number of the latest stable checkpoint in `V`
+    pub fn min_s(view_changes: &[Signed<ViewChange>]) -> u64 {
+        view_changes.iter().map(|v| v.message().last_stable_checkpoint()).max().unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for NewView {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    // the sequence number of the last request whose execution is reflected in the state
+    sequence_number: u64,
+    // the digest of the state
+    digest: String,
+}
+
+impl Checkpoint {
+    pub fn new(sequence_number: u64, digest: String) -> Self {
+        Self { sequence_number, digest }
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    pub fn digest(&self) -> &String {
+        &self.digest
+    }
+}
+
+impl std::fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+// Replica _i_ sends the session key that the recipient uses to authenticate messages sent to _i_
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NewKey {
+    // the replica the key is sent to
+    recipient: String,
+    key: Vec<u8>,
+}
+
+impl NewKey {
+    pub fn new(recipient: &PeerId, key: Vec<u8>) -> Self {
+        Self { recipient: recipient.to_base58(), key }
+    }
+
+    pub fn recipient(&self) -> &String {
+        &self.recipient
+    }
+
+    pub fn key(&self) -> &Vec<u8> {
+        &self.key
+    }
+}
+
+// The key is not printed into the logs
+impl std::fmt::Debug for NewKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "NewKey {{ recipient: {} }}", self.recipient)
+    }
+}
+
+impl std::fmt::Display for NewKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// A replica that has fallen behind asks the other replicas for the state after the last request it
+// has executed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FetchState {
+    last_executed: u64,
+}
+
+impl FetchState {
+    pub fn new(last_executed: u64) -> Self {
+        Self { last_executed }
+    }
+
+    pub fn last_executed(&self) -> u64 {
+        self.last_executed
+    }
+}
+
+impl std::fmt::Display for FetchState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+// The state at a checkpoint: the partitions of the service state and the replies cached for the clients
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CheckpointState {
+    sequence_number: u64,
+    partitions: Vec<Vec<u8>>,
+    replies: Vec<CachedReply>,
+}
+
+impl CheckpointState {
+    pub fn new(sequence_number: u64, partitions: Vec<Vec<u8>>, replies: Vec<CachedReply>) -> Self {
+        Self { sequence_number, partitions, replies }
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    pub fn partition(&self, index: usize) -> Option<&Vec<u8>> {
+        self.partitions.get(index)
+    }
+
+    // The replica sends the digests of the partitions, with the proof of the checkpoint, instead of
+    // the partitions themselves
+    pub fn summary(&self, proof: Vec<Signed<Checkpoint>>) -> CheckpointSummary {
+        CheckpointSummary {
+            sequence_number: self.sequence_number,
+            partition_digests: self.partitions.iter().map(|p| digest(p)).collect(),
+            replies: self.replies.clone(),
+            proof,
+        }
+    }
+}
+
+// The partitions are not printed into the logs
+impl std::fmt::Debug for CheckpointState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CheckpointState {{ sequence_number: {}, partitions: {}, replies: {} }}", self.sequence_number, self.partitions.len(), self.replies.len())
+    }
+}
+
+// A stable checkpoint described by the digests of the partitions of the service state
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CheckpointSummary {
+    sequence_number: u64,
+    partition_digests: Vec<String>,
+    replies: Vec<CachedReply>,
+    proof: Vec<Signed<Checkpoint>>,
+}
+
+impl CheckpointSummary {
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    pub fn partition_digests(&self) -> &Vec<String> {
+        &self.partition_digests
+    }
+
+    pub fn replies(&self) -> &Vec<CachedReply> {
+        &self.replies
+    }
+
+    pub fn proof(&self) -> &Vec<Signed<Checkpoint>> {
+        &self.proof
+    }
+}
+
+// The response to FETCH-STATE: the latest stable checkpoint of the sender if the requester has not
+// reached it, and the requests the sender has executed after the last request the requester has executed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    checkpoint: Option<CheckpointSummary>,
+    committed: Vec<Signed<PrePrepare>>,
+}
+
+impl StateSnapshot {
+    pub fn new(checkpoint: Option<CheckpointSummary>, committed: Vec<Signed<PrePrepare>>) -> Self {
+        Self { checkpoint, committed }
+    }
+
+    pub fn into_parts(self) -> (Option<CheckpointSummary>, Vec<Signed<PrePrepare>>) {
+        (self.checkpoint, self.committed)
+    }
+}
+
+impl std::fmt::Display for StateSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "StateSnapshot {{ checkpoint: {:?}, committed: {:?} }}",
+            self.checkpoint.as_ref().map(|c| c.sequence_number()),
+            self.committed.iter().map(|p| p.message().sequence_number()).collect::<Vec<_>>()
+        )
+    }
+}
+
+// The replica asks for the partitions of the state at the checkpoint whose digests differ from its own
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FetchPartitions {
+    sequence_number: u64,
+    partitions: Vec<usize>,
+}
+
+impl FetchPartitions {
+    pub fn new(sequence_number: u64, partitions: Vec<usize>) -> Self {
+        Self { sequence_number, partitions }
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    pub fn partitions(&self) -> &Vec<usize> {
+        &self.partitions
+    }
+}
+
+impl std::fmt::Display for FetchPartitions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+// The partitions of the state at the checkpoint, keyed by their indexes
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Partitions {
+    sequence_number: u64,
+    partitions: Vec<(usize, Vec<u8>)>,
+}
+
+impl Partitions {
+    pub fn new(sequence_number: u64, partitions: Vec<(usize, Vec<u8>)>) -> Self {
+        Self { sequence_number, partitions }
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    pub fn into_partitions(self) -> Vec<(usize, Vec<u8>)> {
+        self.partitions
+    }
+}
+
+// The partitions are not printed into the logs
+impl std::fmt::Debug for Partitions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Partitions {{ sequence_number: {}, partitions: {:?} }}", self.sequence_number, self.partitions.iter().map(|(i, _)| i).collect::<Vec<_>>())
+    }
+}
+
+impl std::fmt::Display for Partitions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
@@ -1,19 +1,21 @@
 use libp2p::mdns::{Mdns, MdnsEvent};
 use libp2p::swarm::NetworkBehaviourEventProcess;
+use libp2p::swarm::toggle::Toggle;
 use libp2p::NetworkBehaviour;
 use tokio::prelude::{AsyncRead, AsyncWrite};
 use crate::behavior::{Pbft, PbftEvent};
 
 #[derive(NetworkBehaviour)]
 pub struct NetworkBehaviourComposer<TSubstream: AsyncRead + AsyncWrite> {
-    mdns: Mdns<TSubstream>,
+    // mDNS is used to discover the replicas on the local network where it is available
+    mdns: Toggle<Mdns<TSubstream>>,
     pub pbft: Pbft<TSubstream>,
 }
 
 impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourComposer<TSubstream> {
-    pub fn new(mdns: Mdns<TSubstream>, pbft: Pbft<TSubstream>) -> Self {
+    pub fn new(mdns: Option<Mdns<TSubstream>>, pbft: Pbft<TSubstream>) -> Self {
         Self {
-            mdns,
+            mdns: mdns.into(),
             pbft,
         }
     }
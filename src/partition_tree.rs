@@ -0,0 +1,120 @@
+use crate::message::digest;
+
+// The number of children of an interior node
+const BRANCHING: usize = 4;
+
+// The service state is divided into partitions, and the digests of the partitions are the leaves of
+// a tree. The digest of an interior node is the digest of the digests of its children, and the digest
+// of the root is folded into the checkpoint digest. Two states that differ only in a few partitions
+// share most of the tree, so the partitions that differ are found without comparing every leaf.
+#[derive(Debug)]
+pub struct PartitionTree {
+    // The digests of the nodes, from the leaves (`levels[0]`) up to the root
+    levels: Vec<Vec<String>>,
+}
+
+impl PartitionTree {
+    pub fn new(leaves: Vec<String>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let parents = levels.last().unwrap()
+                .chunks(BRANCHING)
+                .map(|children| digest(&serde_json::to_vec(children).unwrap()))
+                .collect();
+            levels.push(parents);
+        }
+        Self { levels }
+    }
+
+    // Builds the tree from the serialized partitions
+    pub fn from_partitions(partitions: &[Vec<u8>]) -> Self {
+        Self::new(partitions.iter().map(|p| digest(p)).collect())
+    }
+
+    pub fn root(&self) -> String {
+        self.levels.last().unwrap().first().cloned().unwrap_or_else(|| digest(&[]))
+    }
+
+    pub fn leaves(&self) -> &Vec<String> {
+        &self.levels[0]
+    }
+
+    // The indexes of the leaves that differ from the other tree. The subtrees whose roots match are
+    // skipped.
+    pub fn differing_leaves(&self, other: &PartitionTree) -> Vec<usize> {
+        if self.leaves().len() != other.leaves().len() {
+            return (0..self.leaves().len()).collect();
+        }
+        if self.leaves().is_empty() {
+            return Vec::new();
+        }
+
+        let mut differing = vec![0];
+        for level in (0..self.levels.len()).rev() {
+            differing.retain(|i| self.levels[level][*i] != other.levels[level][*i]);
+            if level > 0 {
+                let width = self.levels[level - 1].len();
+                differing = differing.iter()
+                    .flat_map(|i| (i * BRANCHING)..std::cmp::min((i + 1) * BRANCHING, width))
+                    .collect();
+            }
+        }
+        differing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tree over `count` partitions, where the partitions at the `changed` indexes differ from the
+    // ones of the other trees
+    fn tree(count: usize, changed: &[usize]) -> PartitionTree {
+        let partitions: Vec<Vec<u8>> = (0..count)
+            .map(|i| if changed.contains(&i) {
+                format!("changed partition {}", i).into_bytes()
+            } else {
+                format!("partition {}", i).into_bytes()
+            })
+            .collect();
+        PartitionTree::from_partitions(&partitions)
+    }
+
+    #[test]
+    fn identical_trees() {
+        assert_eq!(tree(16, &[]).root(), tree(16, &[]).root());
+        assert!(tree(16, &[]).differing_leaves(&tree(16, &[])).is_empty());
+        assert!(tree(0, &[]).differing_leaves(&tree(0, &[])).is_empty());
+    }
+
+    #[test]
+    fn single_differing_leaf() {
+        for &i in [0, 5, 15].iter() {
+            assert_ne!(tree(16, &[]).root(), tree(16, &[i]).root());
+            assert_eq!(tree(16, &[]).differing_leaves(&tree(16, &[i])), vec![i]);
+        }
+    }
+
+    #[test]
+    fn all_leaves_differ() {
+        let all: Vec<usize> = (0..16).collect();
+        assert_eq!(tree(16, &[]).differing_leaves(&tree(16, &all)), all);
+    }
+
+    #[test]
+    fn partition_count_not_a_power_of_the_branching_factor() {
+        // 10 leaves make a level of 3 interior nodes, the last one with only 2 children
+        assert_eq!(tree(10, &[]).differing_leaves(&tree(10, &[9])), vec![9]);
+        assert_eq!(tree(10, &[]).differing_leaves(&tree(10, &[0, 3, 8])), vec![0, 3, 8]);
+        let all: Vec<usize> = (0..10).collect();
+        assert_eq!(tree(10, &[]).differing_leaves(&tree(10, &all)), all);
+
+        assert_eq!(tree(17, &[]).differing_leaves(&tree(17, &[16])), vec![16]);
+        assert_eq!(tree(1, &[]).differing_leaves(&tree(1, &[0])), vec![0]);
+    }
+
+    #[test]
+    fn different_partition_counts() {
+        assert_eq!(tree(4, &[]).differing_leaves(&tree(5, &[])), vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,56 @@
+// The primary keeps at most `window` instances of the protocol in flight, i.e. sequence numbers that
+// have been assigned but not executed yet. The requests beyond the window stay queued in the batcher
+// and are ordered as the earlier instances are executed. Unlike the water marks, which bound the
+// sequence numbers the replicas accept, the window bounds the work the primary starts, so that the
+// latency and the memory usage stay predictable under bursts.
+#[derive(Clone, Copy, Debug)]
+pub struct Pipeline {
+    window: u64, // K
+}
+
+impl Pipeline {
+    pub fn new(window: u64) -> Self {
+        assert!(window > 0, "The pipeline window must be greater than 0");
+        Self { window }
+    }
+
+    pub fn in_flight(&self, last_assigned: u64, last_executed: u64) -> u64 {
+        last_assigned.saturating_sub(last_executed)
+    }
+
+    pub fn is_full(&self, last_assigned: u64, last_executed: u64) -> bool {
+        self.in_flight(last_assigned, last_executed) >= self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_flight() {
+        let pipeline = Pipeline::new(3);
+        assert_eq!(pipeline.in_flight(0, 0), 0);
+        assert_eq!(pipeline.in_flight(5, 2), 3);
+        // A new primary may have executed requests that the previous primaries assigned
+        assert_eq!(pipeline.in_flight(2, 5), 0);
+    }
+
+    #[test]
+    fn is_full() {
+        let pipeline = Pipeline::new(3);
+        assert!(!pipeline.is_full(0, 0));
+        assert!(!pipeline.is_full(2, 0));
+        assert!(pipeline.is_full(3, 0));
+        assert!(pipeline.is_full(4, 0));
+
+        // The pipeline resumes as the instances are executed
+        assert!(!pipeline.is_full(3, 1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_window() {
+        Pipeline::new(0);
+    }
+}
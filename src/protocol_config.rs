@@ -8,13 +8,33 @@ use tokio::codec::Framed;
 use unsigned_varint::codec::UviBytes;
 use crate::message::Message;
 use futures::{Stream, Sink};
+use std::convert::TryFrom;
+use crate::error::PbftError;
 
-#[derive(Clone)]
-pub struct Name;
+// The version of the schema of `Message` in the binary encoding. A message is prefixed by the version
+// so that a message of another schema is rejected instead of being misread. CBOR keeps the names of
+// the fields and the variants, so a field can be added with a default value without bumping the
+// version. Signed and authenticated messages carry the bytes their signature or MACs are computed
+// over, so they still verify on the replicas that drop the field on decoding. When `Message` changes
+// incompatibly, the version is bumped along with the protocol name.
+const BINARY_SCHEMA_VERSION: u8 = 1;
+
+// The versions of the protocol. The replicas propose the binary encoding first and fall back to JSON
+// if the remote doesn't support it, so that replicas of different versions can run in the same cluster.
+#[derive(Clone, Copy, Debug)]
+pub enum Name {
+    // The messages are encoded with CBOR
+    Binary,
+    // The messages are encoded as JSON
+    Json,
+}
 
 impl ProtocolName for Name {
     fn protocol_name(&self) -> &[u8] {
-        b"/ackintosh/pbft/1.0.0"
+        match self {
+            Name::Binary => b"/ackintosh/pbft/2.0.0",
+            Name::Json => b"/ackintosh/pbft/1.0.0",
+        }
     }
 }
 
@@ -23,11 +43,11 @@ pub struct PbftProtocolConfig;
 
 impl UpgradeInfo for PbftProtocolConfig {
     type Info = Name;
-    type InfoIter = std::iter::Once<Self::Info>;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
         println!("Pbft::protocol_info()");
-        std::iter::once(Name{})
+        vec![Name::Binary, Name::Json].into_iter()
     }
 }
 
@@ -42,12 +62,15 @@ where
     fn upgrade_inbound(
         self,
         socket: Negotiated<TSubstream>,
-        _info: Self::Info,
+        info: Self::Info,
     ) -> Self::Future {
-        println!("PbftProtocolConfig::upgrade_inbound");
+        println!("PbftProtocolConfig::upgrade_inbound, info: {:?}", info);
         let codec = UviBytes::default();
+        let decode: fn(BytesMut) -> Result<Message, std::io::Error> = match info {
+            Name::Binary => binary_to_message,
+            Name::Json => json_to_message,
+        };
 
-        // TODO: Protocol Buffers
         futures::future::ok(
             Framed::new(socket, codec)
                 .from_err()
@@ -55,10 +78,7 @@ where
                     println!("[PbftProtocolConfig::upgrade_inbound] [with] response: {:?}", response);
                     Ok(response)
                 })
-                .and_then::<fn(_) -> _, _>(|bytes| {
-                    println!("[PbftProtocolConfig::upgrade_inbound] [and_then]");
-                    Ok(bytes_to_message(&bytes))
-                })
+                .and_then::<fn(_) -> _, _>(decode)
         )
     }
 }
@@ -74,19 +94,19 @@ where
     fn upgrade_outbound(
         self,
         socket: Negotiated<TSubstream>,
-        _info: Self::Info,
+        info: Self::Info,
     ) -> Self::Future {
-        println!("[PbftProtocolConfig::upgrade_outbound]");
+        println!("[PbftProtocolConfig::upgrade_outbound] info: {:?}", info);
         let codec = UviBytes::default();
+        let encode: fn(Message) -> Result<Vec<u8>, std::io::Error> = match info {
+            Name::Binary => message_to_binary,
+            Name::Json => message_to_json,
+        };
 
-        // TODO: Protocol Buffers
         futures::future::ok(
             Framed::new(socket, codec)
                 .from_err()
-                .with::<_, fn(_) -> _, _>(|outbound_message| {
-                    println!("[PbftProtocolConfig::upgrade_outbound] [with] outbound_message : {:?}", outbound_message);
-                    Ok(message_to_json(&outbound_message).into_bytes())
-                })
+                .with::<_, fn(_) -> _, _>(encode)
                 .and_then::<fn(_) -> _, _>(|bytes| {
                     println!("[PbftProtocolConfig::upgrade_outbound] [and_then]");
                     Ok(bytes.to_vec())
@@ -110,19 +130,149 @@ pub type PbftStreamSink<S, A, B> = futures::stream::AndThen<
     Result<B, std::io::Error>,
 >;
 
-fn message_to_json(message: &Message) -> String {
-    let json = match message {
-        Message::PrePrepare(_) | Message::Prepare(_) | Message::Commit(_) => {
-            message.to_string()
-        }
-        Message::ClientRequest(_) => unreachable!()
-    };
+fn message_to_json(message: Message) -> Result<Vec<u8>, std::io::Error> {
+    let json = message.to_string();
     println!("[protocol_config::message_to_json] json: {:?}", json);
-    return json;
+    Ok(json.into_bytes())
 }
 
-fn bytes_to_message(bytes: &BytesMut) -> Message {
-    let message = bytes.to_vec().into();
-    println!("[protocol_config::bytes_to_message] message: {:?}", message);
-    return message;
-}
\ No newline at end of file
+fn message_to_binary(message: Message) -> Result<Vec<u8>, std::io::Error> {
+    println!("[protocol_config::message_to_binary] message: {:?}", message);
+    let mut bytes = vec![BINARY_SCHEMA_VERSION];
+    serde_cbor::to_writer(&mut bytes, &message)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(bytes)
+}
+
+fn json_to_message(bytes: BytesMut) -> Result<Message, std::io::Error> {
+    let message = Message::try_from(&bytes[..]).map_err(malformed_message)?;
+    println!("[protocol_config::json_to_message] message: {:?}", message);
+    Ok(message)
+}
+
+fn binary_to_message(bytes: BytesMut) -> Result<Message, std::io::Error> {
+    let message = match bytes.split_first() {
+        Some((&BINARY_SCHEMA_VERSION, body)) => serde_cbor::from_slice(body)
+            .map_err(|e| PbftError::MalformedMessage(e.to_string())),
+        Some((version, _)) => Err(PbftError::MalformedMessage(format!("Unsupported schema version: {}", version))),
+        None => Err(PbftError::MalformedMessage("The message is empty".to_owned())),
+    }.map_err(malformed_message)?;
+    println!("[protocol_config::binary_to_message] message: {:?}", message);
+    Ok(message)
+}
+
+// A malformed message fails the substream, so the message is dropped without an answer
+fn malformed_message(e: PbftError) -> std::io::Error {
+    eprintln!("[protocol_config] the malformed message was dropped. error: {}", e);
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use serde_cbor::Value;
+    use crate::message::{Signed, Checkpoint};
+
+    fn checkpoint() -> Message {
+        Message::Checkpoint(Signed::new(Checkpoint::new(100, "digest".to_owned()), &Keypair::generate_ed25519()))
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Message, std::io::Error> {
+        binary_to_message(BytesMut::from(bytes))
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let message = checkpoint();
+        let expected = message.to_string();
+        let decoded = decode(message_to_binary(message).unwrap()).unwrap();
+
+        assert_eq!(decoded.to_string(), expected);
+        match decoded {
+            Message::Checkpoint(checkpoint) => assert!(checkpoint.verify().is_ok()),
+            message => panic!("unexpected message: {}", message),
+        }
+    }
+
+    #[test]
+    fn binary_is_smaller_than_json() {
+        let message = checkpoint();
+        let json = message.to_string();
+        assert!(message_to_binary(message).unwrap().len() < json.len());
+    }
+
+    // A message from a replica that knows a field this replica doesn't is still decoded, and its
+    // signature still verifies
+    #[test]
+    fn unknown_fields_are_ignored() {
+        fn add_field(value: &mut Value) {
+            match value {
+                Value::Map(map) => {
+                    for v in map.values_mut() {
+                        add_field(v);
+                    }
+                    // the map of an enum variant has a single entry
+                    if map.len() > 1 {
+                        map.insert(Value::Text("unknown_field".to_owned()), Value::Integer(1));
+                    }
+                }
+                Value::Array(values) => values.iter_mut().for_each(add_field),
+                _ => {}
+            }
+        }
+        fn to_bytes(value: &Value) -> Vec<u8> {
+            match value {
+                Value::Array(values) => values.iter().map(|v| match v {
+                    Value::Integer(byte) => *byte as u8,
+                    v => panic!("unexpected value: {:?}", v),
+                }).collect(),
+                value => panic!("unexpected value: {:?}", value),
+            }
+        }
+        fn from_bytes(bytes: &[u8]) -> Value {
+            Value::Array(bytes.iter().map(|&byte| Value::Integer(byte.into())).collect())
+        }
+        let text = |s: &str| Value::Text(s.to_owned());
+
+        let keypair = Keypair::generate_ed25519();
+        let checkpoint = Signed::new(Checkpoint::new(100, "digest".to_owned()), &keypair);
+        let mut value = serde_cbor::value::to_value(Message::Checkpoint(checkpoint.clone())).unwrap();
+        add_field(&mut value);
+
+        // The newer replica signs the message with the unknown field
+        let signed = match &mut value {
+            Value::Map(map) => match map.get_mut(&text("Checkpoint")) {
+                Some(Value::Map(signed)) => signed,
+                value => panic!("unexpected value: {:?}", value),
+            },
+            value => panic!("unexpected value: {:?}", value),
+        };
+        let mut payload: serde_json::Value = serde_json::from_slice(&to_bytes(&signed[&text("payload")])).unwrap();
+        payload["unknown_field"] = 1.into();
+        let payload = serde_json::to_vec(&payload).unwrap();
+        signed.insert(text("signature"), from_bytes(&keypair.sign(&payload).unwrap()));
+        signed.insert(text("payload"), from_bytes(&payload));
+
+        let mut bytes = vec![BINARY_SCHEMA_VERSION];
+        bytes.extend(serde_cbor::to_vec(&value).unwrap());
+        match decode(bytes).unwrap() {
+            Message::Checkpoint(decoded) => {
+                assert!(decoded.verify().is_ok());
+                assert_eq!(decoded.message().to_string(), checkpoint.message().to_string());
+            }
+            message => panic!("unexpected message: {}", message),
+        }
+    }
+
+    #[test]
+    fn malformed_binary_is_rejected() {
+        let mut bytes = message_to_binary(checkpoint()).unwrap();
+        bytes[0] = BINARY_SCHEMA_VERSION + 1;
+        assert!(decode(bytes).is_err());
+
+        let bytes = message_to_binary(checkpoint()).unwrap();
+        assert!(decode(bytes[..bytes.len() / 2].to_vec()).is_err());
+        assert!(decode(Vec::new()).is_err());
+        assert!(decode(vec![BINARY_SCHEMA_VERSION, 0xff, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+// The quorum sizes for a system of `n` replicas, which tolerates `f = (n - 1) / 3` faulty replicas
+#[derive(Clone, Copy, Debug)]
+pub struct Quorum {
+    replicas: usize,
+}
+
+impl Quorum {
+    pub fn new(replicas: usize) -> Self {
+        assert!(replicas > 0, "[Quorum::new] the number of replicas must be greater than 0");
+        Self { replicas }
+    }
+
+    // The maximum number of replicas that may be faulty
+    pub fn f(&self) -> usize {
+        (self.replicas - 1) / 3
+    }
+
+    // `prepared` requires 2f prepares from different backups that match the pre-prepare
+    pub fn prepare(&self) -> usize {
+        2 * self.f()
+    }
+
+    // `committed-local`, a NEW-VIEW message and a stable checkpoint require 2f + 1 matching messages
+    pub fn strong(&self) -> usize {
+        2 * self.f() + 1
+    }
+
+    // f + 1 matching messages guarantee that at least one of them comes from a non-faulty replica
+    pub fn weak(&self) -> usize {
+        self.f() + 1
+    }
+}
+
+impl std::fmt::Display for Quorum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "n: {}, f: {}", self.replicas, self.f())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quorum_sizes() {
+        // (n, f, 2f, 2f + 1, f + 1)
+        let cases = [(1, 0, 0, 1, 1), (4, 1, 2, 3, 2), (5, 1, 2, 3, 2), (7, 2, 4, 5, 3)];
+        for &(n, f, prepare, strong, weak) in cases.iter() {
+            let quorum = Quorum::new(n);
+            assert_eq!(quorum.f(), f, "n: {}", n);
+            assert_eq!(quorum.prepare(), prepare, "n: {}", n);
+            assert_eq!(quorum.strong(), strong, "n: {}", n);
+            assert_eq!(quorum.weak(), weak, "n: {}", n);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_membership() {
+        Quorum::new(0);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use libp2p::PeerId;
+use tokio::timer::Delay;
+use tokio::prelude::{Async, Future};
+use crate::message::{MessageId, Signed, PrePrepare, Prepare, Commit};
+
+// The protocol messages whose delivery to each peer is tracked. Commit messages are kept
+// unauthenticated, as the session keys change when a peer reconnects, and are authenticated again
+// every time they are sent.
+#[derive(Clone, Debug)]
+pub enum Tracked {
+    PrePrepare(Signed<PrePrepare>),
+    Prepare(Signed<Prepare>),
+    Commit(Commit),
+}
+
+impl Tracked {
+    pub fn id(&self) -> MessageId {
+        match self {
+            Tracked::PrePrepare(pre_prepare) => MessageId::PrePrepare(pre_prepare.message().view(), pre_prepare.message().sequence_number()),
+            Tracked::Prepare(prepare) => MessageId::Prepare(prepare.message().view(), prepare.message().sequence_number()),
+            Tracked::Commit(commit) => MessageId::Commit(commit.view(), commit.sequence_number()),
+        }
+    }
+}
+
+struct Pending {
+    message: Tracked,
+    // the number of times the message has been sent
+    attempts: u32,
+    delay: Delay,
+}
+
+// A message is retransmitted to a peer until the peer acknowledges it, so that a transient connection
+// failure doesn't stall an instance of the protocol. The messages are given up after `max_attempts`
+// or once a checkpoint after them becomes stable, as the peer can catch up with state transfer. The
+// retransmissions to a disconnected peer are paused, and the messages are sent again when it
+// reconnects.
+pub struct Retransmissions {
+    timeout: Duration,
+    max_attempts: u32,
+    pending: HashMap<(PeerId, MessageId), Pending>,
+}
+
+impl Retransmissions {
+    pub fn new(timeout: Duration, max_attempts: u32) -> Self {
+        Self {
+            timeout,
+            max_attempts,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn track(&mut self, peer_id: PeerId, message: Tracked) {
+        self.pending.insert((peer_id, message.id()), Pending {
+            message,
+            attempts: 1,
+            delay: Delay::new(Instant::now() + self.timeout),
+        });
+    }
+
+    // The peer has accepted the message
+    pub fn ack(&mut self, peer_id: PeerId, id: MessageId) {
+        if self.pending.remove(&(peer_id, id.clone())).is_some() {
+            println!("[Retransmissions::ack] id: {:?}", id);
+        }
+    }
+
+    pub fn discard_up_to(&mut self, sequence_number: u64) {
+        self.pending.retain(|(_, id), _| id.sequence_number() > sequence_number);
+    }
+
+    // The peer has reconnected. Returns the messages pending for the peer, in the order of their
+    // sequence numbers, with their attempts and timers reset as they are sent again right away.
+    pub fn resume(&mut self, peer_id: &PeerId) -> Vec<Tracked> {
+        let mut messages = Vec::new();
+        for ((p, _), pending) in self.pending.iter_mut() {
+            if p != peer_id {
+                continue;
+            }
+            pending.attempts = 1;
+            pending.delay = Delay::new(Instant::now() + self.timeout);
+            messages.push(pending.message.clone());
+        }
+        messages.sort_by_key(|message| match message.id() {
+            MessageId::PrePrepare(view, n) => (n, view, 0),
+            MessageId::Prepare(view, n) => (n, view, 1),
+            MessageId::Commit(view, n) => (n, view, 2),
+        });
+        if !messages.is_empty() {
+            println!("[Retransmissions::resume] peer_id: {:?}, messages: {}", peer_id, messages.len());
+        }
+        messages
+    }
+
+    // Returns the messages to the connected peers that have not been acknowledged within the timeout
+    pub fn poll(&mut self, connected_peers: &HashSet<PeerId>) -> Vec<(PeerId, Tracked)> {
+        let mut expired = Vec::new();
+        let max_attempts = self.max_attempts;
+        let timeout = self.timeout;
+
+        self.pending.retain(|(peer_id, id), pending| {
+            // The attempts are not spent while the peer is disconnected
+            if !connected_peers.contains(peer_id) {
+                return true;
+            }
+            match pending.delay.poll() {
+                Ok(Async::NotReady) => true,
+                Ok(Async::Ready(())) if pending.attempts >= max_attempts => {
+                    eprintln!("[Retransmissions::poll] gave up retransmitting the message. peer_id: {:?}, id: {:?}, attempts: {}", peer_id, id, pending.attempts);
+                    false
+                }
+                Ok(Async::Ready(())) => {
+                    println!("[Retransmissions::poll] retransmitting the message. peer_id: {:?}, id: {:?}, attempts: {}", peer_id, id, pending.attempts);
+                    pending.attempts += 1;
+                    pending.delay = Delay::new(Instant::now() + timeout);
+                    // The new timer is polled so that the task is woken up when it expires
+                    let _ = pending.delay.poll();
+                    expired.push((peer_id.clone(), pending.message.clone()));
+                    true
+                }
+                Err(e) => {
+                    eprintln!("[Retransmissions::poll] timer error: {:?}", e);
+                    false
+                }
+            }
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use crate::message::PrePrepare;
+
+    fn peer_id() -> PeerId {
+        PeerId::from_public_key(Keypair::generate_ed25519().public())
+    }
+
+    fn prepare(sequence_number: u64) -> Signed<Prepare> {
+        Signed::new(Prepare::from(&PrePrepare::null(1, sequence_number)), &Keypair::generate_ed25519())
+    }
+
+    #[test]
+    fn messages_to_disconnected_peers_are_kept() {
+        let (a, b) = (peer_id(), peer_id());
+        let mut retransmissions = Retransmissions::new(Duration::from_secs(0), 1);
+        retransmissions.track(a.clone(), Tracked::Prepare(prepare(2)));
+        retransmissions.track(a.clone(), Tracked::Commit(Commit::from(prepare(1).message().clone())));
+        retransmissions.track(a.clone(), Tracked::Prepare(prepare(1)));
+        retransmissions.track(b.clone(), Tracked::Prepare(prepare(1)));
+
+        // a single attempt is allowed, but it is not spent while the peers are disconnected
+        assert!(retransmissions.poll(&HashSet::new()).is_empty());
+
+        let ids: Vec<MessageId> = retransmissions.resume(&a).iter().map(Tracked::id).collect();
+        assert_eq!(ids, vec![MessageId::Prepare(1, 1), MessageId::Commit(1, 1), MessageId::Prepare(1, 2)]);
+        assert_eq!(retransmissions.resume(&b).len(), 1);
+    }
+
+    #[test]
+    fn acknowledged_messages_are_not_resumed() {
+        let a = peer_id();
+        let mut retransmissions = Retransmissions::new(Duration::from_secs(2), 5);
+        retransmissions.track(a.clone(), Tracked::Prepare(prepare(1)));
+        retransmissions.track(a.clone(), Tracked::Prepare(prepare(2)));
+        retransmissions.track(a.clone(), Tracked::Prepare(prepare(3)));
+
+        retransmissions.ack(a.clone(), MessageId::Prepare(1, 2));
+        retransmissions.discard_up_to(1);
+
+        let ids: Vec<MessageId> = retransmissions.resume(&a).iter().map(Tracked::id).collect();
+        assert_eq!(ids, vec![MessageId::Prepare(1, 3)]);
+        assert!(retransmissions.resume(&peer_id()).is_empty());
+    }
+}
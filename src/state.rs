@@ -1,26 +1,63 @@
 use std::sync::{RwLock, Arc};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::view::View;
-use crate::message::{PrePrepare, Prepare, Commit};
+use crate::message::{Signed, Envelope, PrePrepare, Prepare, Commit, ViewChange, Checkpoint, ClientReply, CachedReply, CheckpointState};
 use libp2p::PeerId;
+use std::str::FromStr;
+use crate::wal::{Wal, WalRecord};
 
 pub struct State {
     current_view: Arc<RwLock<View>>,
-    pre_prepares: HashMap<PrePrepareKey, PrePrepare>,
-    prepares: HashMap<PrepareKey, HashMap<PeerId, Prepare>>,
+    // The signed messages are kept so that they can be presented to other replicas as proofs
+    pre_prepares: HashMap<PrePrepareKey, Signed<PrePrepare>>,
+    prepares: HashMap<PrepareKey, HashMap<PeerId, Envelope<Prepare>>>,
     commits: HashMap<CommitKey, HashMap<PeerId, Commit>>,
-    // The timestamp in the last reply this node sent to the client
-    last_timestamp: u64,
+    view_changes: HashMap<u64, HashMap<PeerId, Signed<ViewChange>>>, // keyed by the new view
+    checkpoints: HashMap<CheckpointKey, HashMap<PeerId, Signed<Checkpoint>>>,
+    stable_checkpoint: StableCheckpoint,
+    // The sequence number of the last request which has been executed
+    last_executed: u64,
+    // The last reply this node sent to each client, keyed by the client id
+    last_replies: HashMap<String, ClientReply>,
+    // The state at the checkpoints which are not older than the stable checkpoint, kept so that it can
+    // be transferred to replicas that have fallen behind
+    checkpoint_states: HashMap<u64, CheckpointState>,
+    // The changes to the protocol state are appended to the log so that they survive a restart
+    wal: Option<Wal>,
 }
 
 #[derive(PartialEq, Eq, Hash)]
 struct PrePrepareKey(u64, u64); // (view, sequence_number)
 
 #[derive(PartialEq, Debug, Eq, Hash)]
-struct PrepareKey(u64, u64);// (view, sequence_number)
+struct PrepareKey(u64, u64, String); // (view, sequence_number, digest)
+
+#[derive(PartialEq, Eq, Hash)]
+struct CommitKey(u64, u64, String); // (view, sequence_number, digest)
 
 #[derive(PartialEq, Eq, Hash)]
-struct CommitKey(u64); // view
+struct CheckpointKey(u64, String); // (sequence_number, digest)
+
+// A checkpoint with a proof becomes stable
+pub struct StableCheckpoint {
+    sequence_number: u64,
+    digest: String,
+    proof: Vec<Signed<Checkpoint>>,
+}
+
+impl StableCheckpoint {
+    fn genesis() -> Self {
+        Self { sequence_number: 0, digest: String::new(), proof: Vec::new() }
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    pub fn proof(&self) -> &Vec<Signed<Checkpoint>> {
+        &self.proof
+    }
+}
 
 impl State {
     pub fn new() -> Self {
@@ -29,27 +66,121 @@ impl State {
             pre_prepares: HashMap::new(),
             prepares: HashMap::new(),
             commits: HashMap::new(),
-            last_timestamp: 0,
+            view_changes: HashMap::new(),
+            checkpoints: HashMap::new(),
+            stable_checkpoint: StableCheckpoint::genesis(),
+            last_executed: 0,
+            last_replies: HashMap::new(),
+            checkpoint_states: HashMap::new(),
+            wal: None,
+        }
+    }
+
+    // Rebuilds the state from the records in the log. The records are replayed without being
+    // appended again, and the following changes are appended to the log.
+    pub fn recover(wal: Wal, records: Vec<WalRecord>) -> Self {
+        let mut state = Self::new();
+        println!("[State::recover] replaying {} records", records.len());
+        for record in records {
+            state.replay(record);
+        }
+        state.wal = Some(wal);
+        println!("[State::recover] view: {}, pre_prepares: {}, stable_checkpoint: {}", state.current_view(), state.pre_prepares.len(), state.stable_checkpoint.sequence_number);
+        state
+    }
+
+    fn replay(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::PrePrepare(pre_prepare) => self.insert_pre_prepare(pre_prepare),
+            WalRecord::Prepare(replica, prepare) => match PeerId::from_str(&replica) {
+                Ok(peer_id) => self.insert_prepare(peer_id, prepare),
+                Err(e) => eprintln!("[State::replay] invalid peer id: {}, error: {:?}", replica, e),
+            },
+            WalRecord::Commit(replica, commit) => match PeerId::from_str(&replica) {
+                Ok(peer_id) => self.insert_commit(peer_id, commit),
+                Err(e) => eprintln!("[State::replay] invalid peer id: {}, error: {:?}", replica, e),
+            },
+            WalRecord::ViewChange(replica, view_change) => match PeerId::from_str(&replica) {
+                Ok(peer_id) => self.insert_view_change(peer_id, view_change),
+                Err(e) => eprintln!("[State::replay] invalid peer id: {}, error: {:?}", replica, e),
+            },
+            WalRecord::Checkpoint(replica, checkpoint) => match PeerId::from_str(&replica) {
+                Ok(peer_id) => self.insert_checkpoint(peer_id, checkpoint),
+                Err(e) => eprintln!("[State::replay] invalid peer id: {}, error: {:?}", replica, e),
+            },
+            WalRecord::StartViewChange(view) => self.start_view_change(view),
+            WalRecord::InstallView(view) => self.install_view(view),
+            WalRecord::StableCheckpoint(sequence_number, digest, proof) => self.update_stable_checkpoint(sequence_number, digest, proof),
+        }
+    }
+
+    // A replica must not forget a message it has acted on, so the record is appended before the
+    // change is applied
+    fn log(&mut self, record: WalRecord) {
+        if let Some(wal) = self.wal.as_mut() {
+            if let Err(e) = wal.append(&record) {
+                panic!("[State::log] !!! failed to append the record to the log !!! error: {}", e);
+            }
+        }
+    }
+
+    // The records that rebuild the current state
+    fn snapshot(&self) -> Vec<WalRecord> {
+        let mut records = Vec::new();
+        let view = self.current_view();
+        records.push(if self.is_view_active() { WalRecord::InstallView(view) } else { WalRecord::StartViewChange(view) });
+        records.push(WalRecord::StableCheckpoint(self.stable_checkpoint.sequence_number, self.stable_checkpoint.digest.clone(), self.stable_checkpoint.proof.clone()));
+        records.extend(self.pre_prepares.values().cloned().map(WalRecord::PrePrepare));
+        for prepares in self.prepares.values() {
+            records.extend(prepares.iter().map(|(peer_id, p)| WalRecord::Prepare(peer_id.to_base58(), p.clone())));
+        }
+        for commits in self.commits.values() {
+            records.extend(commits.iter().map(|(peer_id, c)| WalRecord::Commit(peer_id.to_base58(), c.clone())));
         }
+        for view_changes in self.view_changes.values() {
+            records.extend(view_changes.iter().map(|(peer_id, v)| WalRecord::ViewChange(peer_id.to_base58(), v.clone())));
+        }
+        for checkpoints in self.checkpoints.values() {
+            records.extend(checkpoints.iter().map(|(peer_id, c)| WalRecord::Checkpoint(peer_id.to_base58(), c.clone())));
+        }
+        records
     }
 
     pub fn current_view(&self) -> u64 {
         self.current_view.read().unwrap().value()
     }
 
-    pub fn insert_pre_prepare(&mut self, pre_prepare: PrePrepare) {
+    pub fn is_view_active(&self) -> bool {
+        self.current_view.read().unwrap().is_active()
+    }
+
+    pub fn start_view_change(&mut self, view: u64) {
+        self.log(WalRecord::StartViewChange(view));
+        self.current_view.write().unwrap().start_view_change(view);
+    }
+
+    // The view-change messages for the views up to the installed one are no longer needed
+    pub fn install_view(&mut self, view: u64) {
+        self.log(WalRecord::InstallView(view));
+        self.current_view.write().unwrap().install(view);
+        self.view_changes.retain(|v, _| *v > view);
+    }
+
+    pub fn insert_pre_prepare(&mut self, pre_prepare: Signed<PrePrepare>) {
         println!("[State::insert_pre_prepare] The PrePrepare message has been stored into logs: {}", pre_prepare);
+        self.log(WalRecord::PrePrepare(pre_prepare.clone()));
 
         self.pre_prepares.insert(
-            PrePrepareKey(pre_prepare.view(), pre_prepare.sequence_number()),
+            PrePrepareKey(pre_prepare.message().view(), pre_prepare.message().sequence_number()),
             pre_prepare
         );
     }
 
-    pub fn insert_prepare(&mut self, peer_id: PeerId, prepare: Prepare) {
+    pub fn insert_prepare(&mut self, peer_id: PeerId, prepare: Envelope<Prepare>) {
         println!("[State::insert_prepare] The Prepare message has been stored into logs: {}", prepare);
+        self.log(WalRecord::Prepare(peer_id.to_base58(), prepare.clone()));
 
-        let key = PrepareKey(prepare.view(), prepare.sequence_number());
+        let key = PrepareKey(prepare.message().view(), prepare.message().sequence_number(), prepare.message().digest().clone());
         let p = self.prepares
             .entry(key)
             .or_insert(HashMap::new());
@@ -58,36 +189,229 @@ impl State {
 
     pub fn insert_commit(&mut self, peer_id: PeerId, commit: Commit) {
         println!("[State::insert_commit] The Commit message has been stored into logs: {}", commit);
+        self.log(WalRecord::Commit(peer_id.to_base58(), commit.clone()));
 
-        let key = CommitKey(commit.view());
+        let key = CommitKey(commit.view(), commit.sequence_number(), commit.digest().clone());
         let c = self.commits
             .entry(key)
             .or_insert(HashMap::new());
         c.insert(peer_id, commit);
     }
 
-    pub fn prepare_len(&self, view: u64, sequence_number: u64) -> usize {
-        self.prepares.get(&PrepareKey(view, sequence_number)).unwrap().len()
+    pub fn insert_view_change(&mut self, peer_id: PeerId, view_change: Signed<ViewChange>) {
+        println!("[State::insert_view_change] The ViewChange message has been stored into logs: {}", view_change);
+        self.log(WalRecord::ViewChange(peer_id.to_base58(), view_change.clone()));
+
+        let v = self.view_changes
+            .entry(view_change.message().new_view())
+            .or_default();
+        v.insert(peer_id, view_change);
     }
 
-    pub fn commit_len(&self, view: u64) -> usize {
-        self.commits.get(&CommitKey(view)).unwrap().len()
+    // Only the latest checkpoint message of each replica is kept, so that a faulty replica can't fill
+    // the log with checkpoint messages for arbitrary sequence numbers. A later checkpoint of the
+    // replica supersedes the earlier ones, as it becomes stable after them.
+    pub fn insert_checkpoint(&mut self, peer_id: PeerId, checkpoint: Signed<Checkpoint>) {
+        let sequence_number = checkpoint.message().sequence_number();
+        if self.checkpoints.iter().any(|(key, c)| key.0 >= sequence_number && c.contains_key(&peer_id)) {
+            println!("[State::insert_checkpoint] The replica has already sent a checkpoint message for the sequence number or a later one: {}", checkpoint);
+            return;
+        }
+        println!("[State::insert_checkpoint] The Checkpoint message has been stored into logs: {}", checkpoint);
+        self.log(WalRecord::Checkpoint(peer_id.to_base58(), checkpoint.clone()));
+
+        for c in self.checkpoints.values_mut() {
+            c.remove(&peer_id);
+        }
+        self.checkpoints.retain(|_, c| !c.is_empty());
+
+        let key = CheckpointKey(checkpoint.message().sequence_number(), checkpoint.message().digest().clone());
+        let c = self.checkpoints
+            .entry(key)
+            .or_default();
+        c.insert(peer_id, checkpoint);
+    }
+
+    pub fn commit_len(&self, view: u64, sequence_number: u64, digest: &str) -> usize {
+        self.commits.get(&CommitKey(view, sequence_number, digest.to_owned())).map_or(0, |c| c.len())
+    }
+
+    // Whether the log has the commit of the replica for the view, sequence number and digest
+    pub fn has_commit(&self, peer_id: &PeerId, view: u64, sequence_number: u64, digest: &str) -> bool {
+        self.commits.get(&CommitKey(view, sequence_number, digest.to_owned())).and_then(|c| c.get(peer_id)).is_some()
+    }
+
+    pub fn view_change_len(&self, view: u64) -> usize {
+        self.view_changes.get(&view).map_or(0, |v| v.len())
+    }
+
+    pub fn get_view_changes(&self, view: u64) -> Vec<Signed<ViewChange>> {
+        self.view_changes.get(&view).map_or(Vec::new(), |v| v.values().cloned().collect())
+    }
+
+    // The number of replicas that have sent a view-change message for a view greater than `view`
+    pub fn view_change_len_greater_than(&self, view: u64) -> usize {
+        self.view_changes.iter()
+            .filter(|(v, _)| **v > view)
+            .flat_map(|(_, v)| v.keys())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn smallest_view_change_greater_than(&self, view: u64) -> Option<u64> {
+        self.view_changes.keys().filter(|v| **v > view).min().cloned()
+    }
+
+    pub fn get_pre_prepares(&self) -> Vec<&Signed<PrePrepare>> {
+        self.pre_prepares.values().collect()
+    }
+
+    pub fn get_prepares(&self, view: u64, sequence_number: u64, digest: &str) -> Option<&HashMap<PeerId, Envelope<Prepare>>> {
+        self.prepares.get(&PrepareKey(view, sequence_number, digest.to_owned()))
     }
 
     pub fn get_pre_prepare(&self, pre_prepare: &PrePrepare) -> Option<&PrePrepare> {
-        self.pre_prepares.get(&PrePrepareKey(pre_prepare.view(), pre_prepare.sequence_number()))
+        self.pre_prepares.get(&PrePrepareKey(pre_prepare.view(), pre_prepare.sequence_number())).map(|p| p.message())
     }
 
     pub fn get_pre_prepare_by_key(&self, view: u64, sequence_number: u64) -> Option<&PrePrepare> {
-        self.pre_prepares.get(&PrePrepareKey(view, sequence_number))
+        self.pre_prepares.get(&PrePrepareKey(view, sequence_number)).map(|p| p.message())
+    }
+
+    // Whether a pre-prepare for the sequence number has been accepted in any view
+    pub fn has_pre_prepare(&self, sequence_number: u64) -> bool {
+        self.pre_prepares.keys().any(|key| key.1 == sequence_number)
     }
 
-    pub fn last_timestamp(&self) -> u64 {
-        self.last_timestamp
+    pub fn checkpoint_len(&self, sequence_number: u64, digest: &str) -> usize {
+        self.checkpoints.get(&CheckpointKey(sequence_number, digest.to_owned())).map_or(0, |c| c.len())
     }
 
-    pub fn update_last_timestamp(&mut self, timestamp: u64) {
-        println!("[State::update_last_timestamp] updated the timestamp from {:?} to {:?}", self.last_timestamp, timestamp);
-        self.last_timestamp = timestamp;
+    pub fn get_checkpoints(&self, sequence_number: u64, digest: &str) -> Vec<Signed<Checkpoint>> {
+        self.checkpoints.get(&CheckpointKey(sequence_number, digest.to_owned())).map_or(Vec::new(), |c| c.values().cloned().collect())
+    }
+
+    pub fn stable_checkpoint(&self) -> &StableCheckpoint {
+        &self.stable_checkpoint
+    }
+
+    // When a replica has the proof for a checkpoint, the checkpoint becomes stable and the replica
+    // discards all pre-prepare, prepare, and commit messages with sequence number less than or equal
+    // to _n_ from its log; it also discards all earlier checkpoints and checkpoint messages.
+    pub fn update_stable_checkpoint(&mut self, sequence_number: u64, digest: String, proof: Vec<Signed<Checkpoint>>) {
+        self.stable_checkpoint = StableCheckpoint { sequence_number, digest, proof };
+        println!("[State::update_stable_checkpoint] the stable checkpoint has been updated. sequence_number: {}, digest: {}", self.stable_checkpoint.sequence_number, self.stable_checkpoint.digest);
+
+        self.pre_prepares.retain(|key, _| key.1 > sequence_number);
+        self.prepares.retain(|key, _| key.1 > sequence_number);
+        self.commits.retain(|key, _| key.1 > sequence_number);
+        self.checkpoints.retain(|key, _| key.0 >= sequence_number);
+        self.checkpoint_states.retain(|n, _| *n >= sequence_number);
+
+        // The discarded messages are dropped from the log as well
+        let snapshot = self.snapshot();
+        if let Some(wal) = self.wal.as_mut() {
+            if let Err(e) = wal.rewrite(&snapshot) {
+                panic!("[State::update_stable_checkpoint] !!! failed to rewrite the log !!! error: {}", e);
+            }
+        }
+    }
+
+    pub fn last_executed(&self) -> u64 {
+        self.last_executed
+    }
+
+    pub fn is_executed(&self, sequence_number: u64) -> bool {
+        sequence_number <= self.last_executed
+    }
+
+    pub fn record_execution(&mut self, sequence_number: u64) {
+        assert_eq!(sequence_number, self.last_executed + 1, "[State::record_execution] requests must be executed in order");
+        println!("[State::record_execution] last_executed has been updated from {} to {}", self.last_executed, sequence_number);
+        self.last_executed = sequence_number;
+    }
+
+    pub fn last_reply(&self, client_id: &str) -> Option<&ClientReply> {
+        self.last_replies.get(client_id)
+    }
+
+    // The timestamp in the last reply this node sent to the client
+    pub fn last_timestamp(&self, client_id: &str) -> u64 {
+        self.last_reply(client_id).map_or(0, |reply| reply.timestamp())
     }
-}
\ No newline at end of file
+
+    pub fn update_last_reply(&mut self, reply: ClientReply) {
+        println!("[State::update_last_reply] updated the last reply to the client {}. timestamp: {:?} -> {:?}", reply.client_id(), self.last_timestamp(reply.client_id()), reply.timestamp());
+        self.last_replies.insert(reply.client_id().clone(), reply);
+    }
+
+    // The cached replies in the order of the client ids, so that they are the same on every replica
+    pub fn cached_replies(&self) -> Vec<CachedReply> {
+        let mut client_ids: Vec<&String> = self.last_replies.keys().collect();
+        client_ids.sort();
+        client_ids.iter().map(|client_id| self.last_replies[*client_id].to_cached()).collect()
+    }
+
+    pub fn insert_checkpoint_state(&mut self, checkpoint_state: CheckpointState) {
+        println!("[State::insert_checkpoint_state] {:?}", checkpoint_state);
+        self.checkpoint_states.insert(checkpoint_state.sequence_number(), checkpoint_state);
+    }
+
+    pub fn checkpoint_state(&self, sequence_number: u64) -> Option<&CheckpointState> {
+        self.checkpoint_states.get(&sequence_number)
+    }
+
+    // The state at the checkpoint has been transferred from other replicas
+    pub fn restore_checkpoint(&mut self, sequence_number: u64, replies: Vec<ClientReply>) {
+        println!("[State::restore_checkpoint] last_executed has been updated from {} to {}", self.last_executed, sequence_number);
+        self.last_executed = sequence_number;
+        self.last_replies = replies.into_iter().map(|reply| (reply.client_id().clone(), reply)).collect();
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn insert_view_change(state: &mut State, new_view: u64) {
+        let keypair = Keypair::generate_ed25519();
+        let view_change = Signed::new(ViewChange::new(new_view, 0, Vec::new(), Vec::new()), &keypair);
+        state.insert_view_change(PeerId::from_public_key(keypair.public()), view_change);
+    }
+
+    #[test]
+    fn view_changes_are_pruned_when_a_view_is_installed() {
+        let mut state = State::new();
+        for new_view in 2..=4 {
+            insert_view_change(&mut state, new_view);
+        }
+
+        state.install_view(3);
+        assert_eq!(state.view_change_len(2), 0);
+        assert_eq!(state.view_change_len(3), 0);
+        assert_eq!(state.view_change_len(4), 1);
+    }
+
+    fn insert_checkpoint(state: &mut State, keypair: &Keypair, sequence_number: u64) {
+        let checkpoint = Signed::new(Checkpoint::new(sequence_number, "digest".to_owned()), keypair);
+        state.insert_checkpoint(PeerId::from_public_key(keypair.public()), checkpoint);
+    }
+
+    #[test]
+    fn only_the_latest_checkpoint_of_each_replica_is_kept() {
+        let mut state = State::new();
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        insert_checkpoint(&mut state, &a, 100);
+        insert_checkpoint(&mut state, &b, 100);
+        assert_eq!(state.checkpoint_len(100, "digest"), 2);
+
+        insert_checkpoint(&mut state, &a, 1_000_000);
+        assert_eq!(state.checkpoint_len(100, "digest"), 1);
+        assert_eq!(state.checkpoint_len(1_000_000, "digest"), 1);
+
+        // An earlier checkpoint of the replica is ignored
+        insert_checkpoint(&mut state, &a, 200);
+        assert_eq!(state.checkpoint_len(200, "digest"), 0);
+        assert_eq!(state.checkpoints.len(), 2);
+    }
+}
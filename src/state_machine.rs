@@ -0,0 +1,41 @@
+use crate::partition_tree::PartitionTree;
+
+// The service replicated by PBFT. Operations must be deterministic: the execution of an operation in
+// a given state and with a given set of arguments must always produce the same result.
+pub trait StateMachine {
+    // Executes the operation requested by the client and returns the result sent back in the reply
+    fn execute(&mut self, operation: &str) -> String;
+
+    // Serializes the state of the service divided into a fixed number of partitions. The partitions
+    // must be the same on every replica that executed the same operations, as their digests are
+    // folded into the checkpoint digest.
+    fn partitions(&self) -> Vec<Vec<u8>>;
+
+    // Replaces the partitions at the given indexes with the ones serialized by `partitions`
+    fn restore_partitions(&mut self, partitions: &[(usize, Vec<u8>)]) -> Result<(), String>;
+
+    // Serializes the whole state of the service. State transfer only fetches the partitions that
+    // differ, so the default is built on `partitions`.
+    #[allow(dead_code)]
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.partitions()).expect("Vec<Vec<u8>> is always serializable")
+    }
+
+    // Replaces the state of the service with the one serialized by `snapshot`
+    #[allow(dead_code)]
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        let partitions: Vec<Vec<u8>> = serde_json::from_slice(snapshot)
+            .map_err(|e| format!("Failed to restore the snapshot: {:?}", e))?;
+        if partitions.len() != self.partitions().len() {
+            return Err(format!("The snapshot has {} partitions, expected {}", partitions.len(), self.partitions().len()));
+        }
+        let partitions: Vec<(usize, Vec<u8>)> = partitions.into_iter().enumerate().collect();
+        self.restore_partitions(&partitions)
+    }
+
+    // The digest of the state, which is the root of the partition tree folded into the checkpoint digest
+    #[allow(dead_code)]
+    fn digest(&self) -> String {
+        PartitionTree::from_partitions(&self.partitions()).root()
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+use libp2p::PeerId;
+use tokio::timer::Delay;
+use tokio::prelude::{Async, Future};
+use crate::message::{digest, CachedReply, CheckpointSummary, PrePrepare, Signed};
+
+// A replica that has fallen behind, e.g. after a restart or a partition, fetches the state it is
+// missing from the other replicas. The digests of the partitions of the state at a stable checkpoint
+// are verified against the 2f + 1 checkpoint digests in its proof, and only the partitions whose
+// digests differ from the local ones are fetched. A request executed after the checkpoint is accepted once f + 1
+// replicas have sent a matching pre-prepare, as at least one of them is non-faulty and executes only
+// committed requests.
+pub struct StateTransfer {
+    // The replica waits this long for the responses before fetching the state again
+    timeout: Duration,
+    // When the replica sent the last FETCH-STATE message
+    started_at: Option<Instant>,
+    // The pre-prepares of the executed requests, keyed by the sequence number and the digest, along
+    // with the replicas that sent them
+    committed: BTreeMap<u64, MatchingPrePrepares>,
+    // The checkpoint whose partitions are being fetched
+    pending: Option<PendingCheckpoint>,
+    // The sequence number of the checkpoint the replica is catching up to, and the timer after which
+    // it fetches the state (again) if it has not executed the requests up to the checkpoint by then
+    deadline: Option<(u64, Delay)>,
+}
+
+struct PendingCheckpoint {
+    summary: CheckpointSummary,
+    // the replica the partitions are fetched from
+    peer_id: PeerId,
+    missing: HashSet<usize>,
+    fetched: Vec<(usize, Vec<u8>)>,
+}
+
+// The pre-prepares with the same sequence number, keyed by the digest
+type MatchingPrePrepares = HashMap<String, (Signed<PrePrepare>, HashSet<PeerId>)>;
+
+// The checkpoint summary with the partitions fetched for it, keyed by their indexes
+type FetchedCheckpoint = (CheckpointSummary, Vec<(usize, Vec<u8>)>);
+
+impl StateTransfer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            started_at: None,
+            committed: BTreeMap::new(),
+            pending: None,
+            deadline: None,
+        }
+    }
+
+    pub fn is_in_progress(&self) -> bool {
+        match self.started_at {
+            Some(started_at) => started_at.elapsed() < self.timeout,
+            None => false,
+        }
+    }
+
+    // Starts fetching the state to catch up to the checkpoint with the sequence number. Returns false
+    // if a transfer is already in progress.
+    pub fn start(&mut self, sequence_number: u64) -> bool {
+        if self.is_in_progress() {
+            self.catch_up(sequence_number);
+            return false;
+        }
+        println!("[StateTransfer::start] sequence_number: {}, timeout: {:?}", sequence_number, self.timeout);
+        self.started_at = Some(Instant::now());
+        self.committed.clear();
+        self.pending = None;
+        let target = self.deadline.as_ref().map_or(sequence_number, |(target, _)| std::cmp::max(*target, sequence_number));
+        self.deadline = Some((target, Delay::new(Instant::now() + self.timeout)));
+        true
+    }
+
+    // The replica is executing the requests up to the checkpoint from its log. The deadline that is
+    // already running is kept, so that a replica that keeps falling behind still fetches the state.
+    pub fn catch_up(&mut self, sequence_number: u64) {
+        match self.deadline.as_mut() {
+            Some((target, _)) => *target = std::cmp::max(*target, sequence_number),
+            None => self.deadline = Some((sequence_number, Delay::new(Instant::now() + self.timeout))),
+        }
+    }
+
+    // Returns the sequence number of the checkpoint the replica is catching up to once the deadline
+    // expires before the replica has executed the requests up to it
+    pub fn poll(&mut self, last_executed: u64) -> Async<u64> {
+        let target = match self.deadline.as_mut() {
+            Some((target, _)) if *target <= last_executed => None,
+            Some((target, delay)) => match delay.poll() {
+                Ok(Async::NotReady) => return Async::NotReady,
+                Ok(Async::Ready(())) => Some(*target),
+                Err(e) => {
+                    eprintln!("[StateTransfer::poll] timer error: {:?}", e);
+                    Some(*target)
+                }
+            },
+            None => return Async::NotReady,
+        };
+        self.deadline = None;
+
+        match target {
+            Some(target) => {
+                println!("[StateTransfer::poll] the replica has not caught up to the checkpoint in time. sequence_number: {}, last_executed: {}", target, last_executed);
+                // The transfer in progress, if any, has timed out as well
+                self.started_at = None;
+                Async::Ready(target)
+            }
+            None => Async::NotReady,
+        }
+    }
+
+    pub fn insert_committed(&mut self, peer_id: PeerId, pre_prepare: Signed<PrePrepare>) {
+        let (_, replicas) = self.committed
+            .entry(pre_prepare.message().sequence_number())
+            .or_default()
+            .entry(pre_prepare.message().digest().clone())
+            .or_insert_with(|| (pre_prepare, HashSet::new()));
+        replicas.insert(peer_id);
+    }
+
+    // Returns the pre-prepare of the request executed with the sequence number once `weak` replicas
+    // have sent a matching one
+    pub fn take_committed(&mut self, sequence_number: u64, weak: usize) -> Option<Signed<PrePrepare>> {
+        self.committed = self.committed.split_off(&sequence_number);
+
+        let pre_prepare = self.committed.get(&sequence_number)?
+            .values()
+            .find(|(_, replicas)| replicas.len() >= weak)
+            .map(|(pre_prepare, _)| pre_prepare.clone())?;
+        self.committed.remove(&sequence_number);
+        println!("[StateTransfer::take_committed] sequence_number: {}", sequence_number);
+        Some(pre_prepare)
+    }
+
+    // The sequence number of the checkpoint whose partitions are being fetched
+    pub fn pending_checkpoint(&self) -> Option<u64> {
+        self.pending.as_ref().map(|p| p.summary.sequence_number())
+    }
+
+    pub fn fetch_partitions(&mut self, summary: CheckpointSummary, peer_id: PeerId, missing: Vec<usize>) {
+        println!("[StateTransfer::fetch_partitions] sequence_number: {}, peer_id: {:?}, missing: {:?}", summary.sequence_number(), peer_id, missing);
+        self.pending = Some(PendingCheckpoint {
+            summary,
+            peer_id,
+            missing: missing.into_iter().collect(),
+            fetched: Vec::new(),
+        });
+    }
+
+    // The partition is accepted only if its digest matches the one in the checkpoint summary
+    pub fn insert_partition(&mut self, peer_id: &PeerId, sequence_number: u64, index: usize, partition: Vec<u8>) -> Result<(), String> {
+        let pending = match self.pending.as_mut() {
+            Some(pending) if pending.summary.sequence_number() == sequence_number && &pending.peer_id == peer_id => pending,
+            _ => return Err(format!("The partitions of the checkpoint are not being fetched from the peer. sequence_number: {}, peer_id: {:?}", sequence_number, peer_id)),
+        };
+
+        if !pending.missing.contains(&index) {
+            return Err(format!("The partition is not missing. index: {}", index));
+        }
+        if pending.summary.partition_digests().get(index) != Some(&digest(&partition)) {
+            return Err(format!("The digest of the partition doesn't match the checkpoint. index: {}", index));
+        }
+        pending.missing.remove(&index);
+        pending.fetched.push((index, partition));
+        Ok(())
+    }
+
+    // Returns the checkpoint and the fetched partitions once all the missing partitions have been fetched
+    pub fn take_fetched_checkpoint(&mut self) -> Option<FetchedCheckpoint> {
+        if !self.pending.as_ref()?.missing.is_empty() {
+            return None;
+        }
+        self.pending.take().map(|p| (p.summary, p.fetched))
+    }
+}
+
+// The checkpoint digest covers the replies cached for the clients as well as the service state, as
+// both are transferred to the replicas that have fallen behind. Only the client, the timestamp and
+// the result of a reply are covered, which are the same at every non-faulty replica.
+pub fn checkpoint_digest(service_digest: &str, replies: &[CachedReply]) -> String {
+    let replies: Vec<(&String, u64, &String)> = replies.iter()
+        .map(|reply| (reply.client_id(), reply.timestamp(), reply.result()))
+        .collect();
+    digest(&serde_json::to_vec(&(service_digest, replies)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use libp2p::PeerId;
+    use crate::message::{ClientReply, ClientRequest, Commit, Prepare};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::prelude::future;
+
+    fn cached_reply(client_request: &ClientRequest, view: u64) -> CachedReply {
+        let pre_prepare = PrePrepare::from(view, 1, vec![client_request.clone()]);
+        let commit = Commit::from(Prepare::from(&pre_prepare));
+        let peer_id = PeerId::from_public_key(Keypair::generate_ed25519().public());
+        ClientReply::new(peer_id, client_request, &commit, "OK".to_owned()).to_cached()
+    }
+
+    #[test]
+    fn checkpoint_digest_does_not_depend_on_the_view() {
+        let client = Keypair::generate_ed25519();
+        let client_request = ClientRequest::new("PUT key value".to_owned(), 1, "127.0.0.1:9000".parse().unwrap(), &client);
+
+        assert_eq!(
+            checkpoint_digest("root", &[cached_reply(&client_request, 1)]),
+            checkpoint_digest("root", &[cached_reply(&client_request, 2)]),
+        );
+        assert_ne!(
+            checkpoint_digest("root", &[cached_reply(&client_request, 1)]),
+            checkpoint_digest("other root", &[cached_reply(&client_request, 1)]),
+        );
+    }
+
+    // Polls the state transfer until its deadline expires, or until `wait` has elapsed. Returns the
+    // checkpoint the replica is catching up to if the deadline expired.
+    fn expires_within(state_transfer: &mut StateTransfer, last_executed: u64, wait: Duration) -> Option<u64> {
+        let mut deadline = Delay::new(Instant::now() + wait);
+        Runtime::new().unwrap().block_on(future::poll_fn(|| -> Result<Async<Option<u64>>, ()> {
+            if let Async::Ready(sequence_number) = state_transfer.poll(last_executed) {
+                return Ok(Async::Ready(Some(sequence_number)));
+            }
+            match deadline.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Ok(Async::Ready(None)),
+            }
+        })).unwrap()
+    }
+
+    #[test]
+    fn falls_back_to_state_transfer_if_the_log_does_not_reach_the_checkpoint() {
+        let mut state_transfer = StateTransfer::new(Duration::from_millis(10));
+        state_transfer.catch_up(100);
+        state_transfer.catch_up(200);
+
+        assert_eq!(expires_within(&mut state_transfer, 150, Duration::from_millis(500)), Some(200));
+        // The deadline fires once
+        assert_eq!(expires_within(&mut state_transfer, 150, Duration::from_millis(50)), None);
+    }
+
+    #[test]
+    fn deadline_is_cleared_once_the_checkpoint_is_reached() {
+        let mut state_transfer = StateTransfer::new(Duration::from_millis(10));
+        state_transfer.catch_up(100);
+
+        assert_eq!(expires_within(&mut state_transfer, 100, Duration::from_millis(50)), None);
+    }
+
+    #[test]
+    fn transfer_is_retried_until_the_checkpoint_is_reached() {
+        let mut state_transfer = StateTransfer::new(Duration::from_millis(10));
+        assert!(state_transfer.start(100));
+        assert!(!state_transfer.start(100));
+
+        assert_eq!(expires_within(&mut state_transfer, 50, Duration::from_millis(500)), Some(100));
+        assert!(!state_transfer.is_in_progress());
+        assert!(state_transfer.start(100));
+    }
+}
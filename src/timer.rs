@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+use tokio::prelude::{Async, Future};
+
+// The back-off stops doubling the timeout after this many successive view changes, so that the
+// timeout doesn't overflow while the replica keeps failing to install a new view
+const MAX_BACK_OFF_EXPONENT: u32 = 10;
+
+// Backups use the timers to detect a faulty primary. A timer is started when a backup receives a
+// request and stopped when the request has been executed.
+pub struct RequestTimers {
+    timeout: Duration,
+    // The number of view changes that have been started without receiving a valid NEW-VIEW message
+    view_change_attempts: u32,
+    requests: HashMap<String, Delay>, // keyed by the digest of the request
+    view_change: Option<Delay>,
+}
+
+impl RequestTimers {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            view_change_attempts: 0,
+            requests: HashMap::new(),
+            view_change: None,
+        }
+    }
+
+    pub fn start(&mut self, digest: &str) {
+        if self.requests.contains_key(digest) {
+            return;
+        }
+        println!("[RequestTimers::start] digest: {}, timeout: {:?}", digest, self.timeout);
+        self.requests.insert(digest.to_owned(), Delay::new(Instant::now() + self.timeout));
+    }
+
+    pub fn stop(&mut self, digest: &str) {
+        if self.requests.remove(digest).is_some() {
+            println!("[RequestTimers::stop] digest: {}", digest);
+        }
+    }
+
+    // If the timer expires before the replica receives a valid NEW-VIEW message for _v_ + 1, it
+    // starts the view change for view _v_ + 2 but this time it waits `2T` before starting a view
+    // change for view _v_ + 3.
+    pub fn start_view_change(&mut self) {
+        let timeout = self.view_change_timeout();
+        println!("[RequestTimers::start_view_change] attempts: {}, timeout: {:?}", self.view_change_attempts, timeout);
+        self.requests.clear();
+        self.view_change = Some(Delay::new(Instant::now() + timeout));
+        self.view_change_attempts += 1;
+    }
+
+    fn view_change_timeout(&self) -> Duration {
+        self.timeout * 2u32.pow(std::cmp::min(self.view_change_attempts, MAX_BACK_OFF_EXPONENT))
+    }
+
+    // Whether the replica is waiting for a valid NEW-VIEW message
+    #[allow(dead_code)]
+    pub fn is_changing_view(&self) -> bool {
+        self.view_change.is_some()
+    }
+
+    pub fn complete_view_change(&mut self) {
+        println!("[RequestTimers::complete_view_change]");
+        self.view_change = None;
+        self.view_change_attempts = 0;
+    }
+
+    // Returns `Ready` if any of the timers has expired
+    pub fn poll(&mut self) -> Async<()> {
+        let mut expired = false;
+
+        self.requests.retain(|digest, delay| {
+            match delay.poll() {
+                Ok(Async::NotReady) => true,
+                Ok(Async::Ready(())) => {
+                    println!("[RequestTimers::poll] the request timer has expired. digest: {}", digest);
+                    expired = true;
+                    false
+                }
+                Err(e) => {
+                    eprintln!("[RequestTimers::poll] timer error: {:?}", e);
+                    false
+                }
+            }
+        });
+
+        if let Some(delay) = self.view_change.as_mut() {
+            match delay.poll() {
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(())) => {
+                    println!("[RequestTimers::poll] the view change timer has expired");
+                    self.view_change = None;
+                    expired = true;
+                }
+                Err(e) => {
+                    eprintln!("[RequestTimers::poll] timer error: {:?}", e);
+                    self.view_change = None;
+                }
+            }
+        }
+
+        if expired {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::prelude::future;
+
+    // Polls the timers until they expire, or until `wait` has elapsed. Returns whether they expired.
+    fn expires_within(timers: &mut RequestTimers, wait: Duration) -> bool {
+        let mut deadline = Delay::new(Instant::now() + wait);
+        Runtime::new().unwrap().block_on(future::poll_fn(|| -> Result<Async<bool>, ()> {
+            if let Async::Ready(()) = timers.poll() {
+                return Ok(Async::Ready(true));
+            }
+            match deadline.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Ok(Async::Ready(false)),
+            }
+        })).unwrap()
+    }
+
+    #[test]
+    fn request_timer_expires() {
+        let mut timers = RequestTimers::new(Duration::from_millis(10));
+        timers.start("digest");
+        assert!(expires_within(&mut timers, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn stopped_request_timer_does_not_expire() {
+        let mut timers = RequestTimers::new(Duration::from_millis(10));
+        timers.start("digest");
+        timers.stop("digest");
+        assert!(!expires_within(&mut timers, Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn view_change_timeout_doubles() {
+        let mut timers = RequestTimers::new(Duration::from_secs(10));
+        assert_eq!(timers.view_change_timeout(), Duration::from_secs(10));
+        timers.start_view_change();
+        assert_eq!(timers.view_change_timeout(), Duration::from_secs(20));
+        timers.start_view_change();
+        assert_eq!(timers.view_change_timeout(), Duration::from_secs(40));
+
+        timers.complete_view_change();
+        assert_eq!(timers.view_change_timeout(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn view_change_timeout_is_capped() {
+        let mut timers = RequestTimers::new(Duration::from_secs(10));
+        for _ in 0..100 {
+            timers.start_view_change();
+        }
+        assert_eq!(timers.view_change_timeout(), Duration::from_secs(10) * 2u32.pow(MAX_BACK_OFF_EXPONENT));
+    }
+
+    #[test]
+    fn view_change_clears_the_request_timers() {
+        let mut timers = RequestTimers::new(Duration::from_millis(10));
+        timers.start("digest");
+        timers.start_view_change();
+        timers.complete_view_change();
+        assert!(!expires_within(&mut timers, Duration::from_millis(50)));
+    }
+}
@@ -1,13 +1,33 @@
 pub struct View {
     value: u64,
+    // `false` while the replica is waiting for a valid NEW-VIEW message for this view
+    active: bool,
 }
 
 impl View {
     pub fn new() -> Self {
-        Self { value: 1 }
+        Self { value: 1, active: true }
     }
 
     pub fn value(&self) -> u64 {
         self.value
     }
-}
\ No newline at end of file
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // The replica moves to the view and stops accepting messages other than VIEW-CHANGE and NEW-VIEW
+    pub fn start_view_change(&mut self, value: u64) {
+        println!("[View::start_view_change] view has been changed from {} to {} (inactive)", self.value, value);
+        self.value = value;
+        self.active = false;
+    }
+
+    // The replica enters the view after accepting a NEW-VIEW message
+    pub fn install(&mut self, value: u64) {
+        println!("[View::install] view has been changed from {} to {} (active)", self.value, value);
+        self.value = value;
+        self.active = true;
+    }
+}
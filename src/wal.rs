@@ -0,0 +1,234 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use crate::message::{Signed, Envelope, PrePrepare, Prepare, Commit, ViewChange, Checkpoint};
+
+// When the appended records are flushed to the disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    // fsync after every record, so that a crash never loses a message the replica has acted on
+    Always,
+    // fsync at most once per interval. A crash may lose the records appended during the interval.
+    Interval(Duration),
+    // leave the flushing to the OS
+    Never,
+}
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => {
+                let millis = s.strip_prefix("interval:")
+                    .and_then(|millis| millis.parse().ok())
+                    .ok_or_else(|| format!("Invalid sync policy: {}", s))?;
+                Ok(SyncPolicy::Interval(Duration::from_millis(millis)))
+            }
+        }
+    }
+}
+
+// The changes to the protocol state, in the order they have been applied. The replicas are
+// identified by their base58-encoded peer ids.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WalRecord {
+    PrePrepare(Signed<PrePrepare>),
+    Prepare(String, Envelope<Prepare>),
+    Commit(String, Commit),
+    ViewChange(String, Signed<ViewChange>),
+    Checkpoint(String, Signed<Checkpoint>),
+    StartViewChange(u64),
+    InstallView(u64),
+    StableCheckpoint(u64, String, Vec<Signed<Checkpoint>>),
+}
+
+// An append-only log of the protocol state. Each record is stored as a line of JSON.
+pub struct Wal {
+    path: String,
+    file: File,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+}
+
+impl Wal {
+    // Opens the log at `path`, creating it if it doesn't exist, and returns the records in it
+    pub fn open(path: &str, sync_policy: SyncPolicy) -> Result<(Self, Vec<WalRecord>), String> {
+        if let Some(dir) = Path::new(path).parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create the directory for {}. error: {}", path, e))?;
+        }
+
+        let records = if Path::new(path).exists() {
+            Self::read(path)?
+        } else {
+            Vec::new()
+        };
+        println!("[Wal::open] path: {}, sync_policy: {:?}, records: {}", path, sync_policy, records.len());
+
+        let wal = Self {
+            path: path.to_owned(),
+            file: Self::open_for_append(path)?,
+            sync_policy,
+            last_sync: Instant::now(),
+        };
+        Ok((wal, records))
+    }
+
+    fn open_for_append(path: &str) -> Result<File, String> {
+        OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Failed to open {}. error: {}", path, e))
+    }
+
+    fn read(path: &str) -> Result<Vec<WalRecord>, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open {}. error: {}", path, e))?;
+        let lines = BufReader::new(file).lines()
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| format!("Failed to read {}. error: {}", path, e))?;
+
+        let mut records = Vec::with_capacity(lines.len());
+        for (i, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                // The last record may have been partially written when the replica crashed
+                Err(e) if i == lines.len() - 1 => {
+                    eprintln!("[Wal::read] the torn record at the end of the log was ignored. error: {}", e);
+                }
+                Err(e) => return Err(format!("The log {} is corrupted at line {}. error: {}", path, i + 1, e)),
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), String> {
+        let mut line = serde_json::to_vec(record).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        self.file.write_all(&line).map_err(|e| format!("Failed to append to {}. error: {}", self.path, e))?;
+
+        let sync = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        self.file.sync_data().map_err(|e| format!("Failed to sync {}. error: {}", self.path, e))?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // Replaces the log with `records` so that it doesn't grow without bound. The new log is written
+    // to a temporary file which is renamed over the old one, so a crash leaves either of them intact.
+    pub fn rewrite(&mut self, records: &[WalRecord]) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", self.path);
+        {
+            let mut tmp = File::create(&tmp_path).map_err(|e| format!("Failed to create {}. error: {}", tmp_path, e))?;
+            for record in records {
+                let mut line = serde_json::to_vec(record).map_err(|e| e.to_string())?;
+                line.push(b'\n');
+                tmp.write_all(&line).map_err(|e| format!("Failed to write to {}. error: {}", tmp_path, e))?;
+            }
+            tmp.sync_all().map_err(|e| format!("Failed to sync {}. error: {}", tmp_path, e))?;
+        }
+        std::fs::rename(&tmp_path, &self.path).map_err(|e| format!("Failed to rename {} to {}. error: {}", tmp_path, self.path, e))?;
+
+        self.file = Self::open_for_append(&self.path)?;
+        self.last_sync = Instant::now();
+        println!("[Wal::rewrite] the log has been rewritten. path: {}, records: {}", self.path, records.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A path to a log that doesn't exist yet, unique to the test
+    fn path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("pbft-wal-{}", std::process::id()));
+        let path = dir.join(format!("{}.log", name));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn views(records: &[WalRecord]) -> Vec<u64> {
+        records.iter().map(|record| match record {
+            WalRecord::InstallView(view) => *view,
+            record => panic!("unexpected record: {:?}", record),
+        }).collect()
+    }
+
+    #[test]
+    fn append_and_read() {
+        let path = path("append_and_read");
+        let (mut wal, records) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        assert!(records.is_empty());
+        for view in 1..=3 {
+            wal.append(&WalRecord::InstallView(view)).unwrap();
+        }
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(views(&records), vec![1, 2, 3]);
+        wal.append(&WalRecord::InstallView(4)).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(views(&records), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn torn_tail_is_ignored() {
+        let path = path("torn_tail_is_ignored");
+        let (mut wal, _) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        wal.append(&WalRecord::InstallView(1)).unwrap();
+        wal.file.write_all(b"{\"InstallView\":").unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(views(&records), vec![1]);
+    }
+
+    #[test]
+    fn corrupted_record_is_an_error() {
+        let path = path("corrupted_record_is_an_error");
+        let (mut wal, _) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        wal.file.write_all(b"garbage\n").unwrap();
+        wal.append(&WalRecord::InstallView(1)).unwrap();
+        drop(wal);
+
+        assert!(Wal::open(&path, SyncPolicy::Never).is_err());
+    }
+
+    #[test]
+    fn rewrite() {
+        let path = path("rewrite");
+        let (mut wal, _) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        for view in 1..=3 {
+            wal.append(&WalRecord::InstallView(view)).unwrap();
+        }
+        wal.rewrite(&[WalRecord::InstallView(3)]).unwrap();
+        wal.append(&WalRecord::InstallView(4)).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(views(&records), vec![3, 4]);
+    }
+
+    #[test]
+    fn parse_sync_policy() {
+        assert_eq!("always".parse::<SyncPolicy>(), Ok(SyncPolicy::Always));
+        assert_eq!("never".parse::<SyncPolicy>(), Ok(SyncPolicy::Never));
+        assert_eq!("interval:100".parse::<SyncPolicy>(), Ok(SyncPolicy::Interval(Duration::from_millis(100))));
+        assert!("interval:".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+}
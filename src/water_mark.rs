@@ -0,0 +1,66 @@
+// The low water mark _h_ is equal to the sequence number of the last stable checkpoint. The high water
+// mark _H = h + k_, where _k_ is big enough so that replicas do not stall waiting for a checkpoint to
+// become stable.
+#[derive(Clone, Copy, Debug)]
+pub struct WaterMarks {
+    window: u64, // k
+}
+
+impl WaterMarks {
+    pub fn new(window: u64) -> Self {
+        Self { window }
+    }
+
+    // The sequence number is valid if it is between the low water mark (exclusive) and the high water mark (inclusive)
+    pub fn check(&self, low: u64, sequence_number: u64) -> Result<(), OutOfWaterMarks> {
+        let high = low + self.window;
+        if sequence_number > low && sequence_number <= high {
+            Ok(())
+        } else {
+            Err(OutOfWaterMarks { sequence_number, low, high })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OutOfWaterMarks {
+    sequence_number: u64,
+    low: u64,
+    high: u64,
+}
+
+impl std::error::Error for OutOfWaterMarks {
+}
+
+impl std::fmt::Display for OutOfWaterMarks {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "The sequence number is not between the water marks. sequence_number: {}, h: {}, H: {}", self.sequence_number, self.low, self.high)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_numbers_between_the_water_marks() {
+        let water_marks = WaterMarks::new(200);
+        assert!(water_marks.check(0, 1).is_ok());
+        assert!(water_marks.check(0, 200).is_ok());
+        assert!(water_marks.check(100, 101).is_ok());
+        assert!(water_marks.check(100, 300).is_ok());
+    }
+
+    #[test]
+    fn sequence_numbers_out_of_the_water_marks() {
+        let water_marks = WaterMarks::new(200);
+        // The low water mark is exclusive, as the requests up to it are covered by the stable checkpoint
+        assert!(water_marks.check(0, 0).is_err());
+        assert!(water_marks.check(100, 100).is_err());
+        assert!(water_marks.check(100, 50).is_err());
+        // The high water mark is inclusive
+        assert!(water_marks.check(0, 201).is_err());
+        assert!(water_marks.check(100, 301).is_err());
+        assert!(water_marks.check(100, u64::MAX).is_err());
+    }
+}